@@ -1,8 +1,9 @@
-extern crate klu;
+extern crate klu_core;
 
 fn main() {
-    let mut archive = klu::read::Archive::from_path("./test/archive.klu");
-    assert_eq!(archive.path_exist("in/jesuisune.jpg"), true);
-    assert_eq!(archive.path_exist("in/inexistant"), false);
-    assert_eq!(archive.path_exist("path/that/do/not/exist/"), false);
+    let archive =
+        klu_core::read::Archive::from_path("./test/archive.klu").expect("Read archive error");
+    assert!(archive.path_exist("in/jesuisune.jpg"));
+    assert!(!archive.path_exist("in/inexistant"));
+    assert!(!archive.path_exist("path/that/do/not/exist/"));
 }
@@ -1,13 +1,13 @@
 extern crate klu_core;
-use std::io::prelude::*;
 #[cfg(feature = "virtual_fs")]
 fn main() {
-    let mut archive =
+    use std::io::prelude::*;
+    let archive =
         klu_core::read::Archive::from_path("./test/archive.klu").expect("Unable to open archive");
     let mut reader = archive.get_virtual("archive/testfile").unwrap();
     let mut buffer = [0; 8];
     reader
-        .read(&mut buffer)
+        .read_exact(&mut buffer)
         .expect("Error while reading in VirtualFile");
     assert_eq!(buffer, [0xFF, 0xEE, 0xDD, 0xCC, 0xBB, 0xAA, 0x99, 0x88]);
 
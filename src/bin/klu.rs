@@ -0,0 +1,261 @@
+//! `klu`, a command-line tool to create, extract and look inside KLU archives
+use klu_core::{diff, read, write};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+const USAGE: &str = "\
+Usage:
+    klu create <path>... [-o <out.klu>]
+    klu extract <archive> [paths...] [-C <dir>]
+    klu list [-l] [--json] <archive>
+    klu cat <archive> <path>
+    klu inspect <archive>
+    klu diff <old.klu> <new.klu|dir>
+";
+
+type CliResult = Result<(), Box<dyn std::error::Error>>;
+
+/// Bad command-line arguments, reported along with [USAGE]
+#[derive(Debug)]
+struct Usage(String);
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Usage {}
+
+fn usage<T>(message: &str) -> Result<T, Box<dyn std::error::Error>> {
+    Err(Box::new(Usage(message.to_owned())))
+}
+
+/// The arguments of a subcommand, split into flags, options taking a value and positionals
+struct Args {
+    positional: Vec<String>,
+    flags: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl Args {
+    /// Parse `args`, `flags` and `options` listing the names accepted by the subcommand
+    fn parse(args: &[String], flags: &[&str], options: &[&str]) -> Result<Self, Usage> {
+        let mut parsed = Args {
+            positional: Vec::new(),
+            flags: Vec::new(),
+            options: HashMap::new(),
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                parsed.positional.extend(args.cloned());
+                break;
+            } else if flags.contains(&arg.as_str()) {
+                parsed.flags.push(arg.clone());
+            } else if options.contains(&arg.as_str()) {
+                let value = args
+                    .next()
+                    .ok_or_else(|| Usage(format!("{} needs a value", arg)))?;
+                parsed.options.insert(arg.clone(), value.clone());
+            } else if arg.starts_with('-') && arg.len() > 1 {
+                return Err(Usage(format!("unknown option {}", arg)));
+            } else {
+                parsed.positional.push(arg.clone());
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+}
+
+fn main() {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let rest = args.get(1..).unwrap_or(&[]);
+    let result = match args.first().map(String::as_str) {
+        Some("create") => create(rest),
+        Some("extract") => extract(rest),
+        Some("list") => list(rest),
+        Some("cat") => cat(rest),
+        Some("inspect") => inspect(rest),
+        Some("diff") => diff(rest),
+        Some("help") | Some("-h") | Some("--help") => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+        Some(command) => usage(&format!("unknown command {}", command)),
+        None => usage("missing command"),
+    };
+    if let Err(e) = result {
+        eprintln!("klu: {}", e);
+        if e.is::<Usage>() {
+            eprint!("{}", USAGE);
+            std::process::exit(2);
+        }
+        std::process::exit(1);
+    }
+}
+
+fn create(args: &[String]) -> CliResult {
+    let args = Args::parse(args, &[], &["-o"])?;
+    let out = match (args.options.get("-o"), args.positional.as_slice()) {
+        (_, []) => return usage("create needs a path"),
+        (Some(out), _) => PathBuf::from(out),
+        (None, [dir]) => {
+            let name = Path::new(dir)
+                .canonicalize()?
+                .file_name()
+                .map(|name| name.to_os_string())
+                .ok_or_else(|| Usage("can't name the archive, use -o".to_owned()))?;
+            PathBuf::from(name).with_extension("klu")
+        }
+        (None, _) => return usage("an archive of several paths needs -o"),
+    };
+    let archive = match args.positional.as_slice() {
+        [path] => write::Archive::from_path(path)?,
+        paths => write::Archive::from_paths(paths)?,
+    };
+    archive.write_to_path(out)?;
+    Ok(())
+}
+
+fn extract(args: &[String]) -> CliResult {
+    let args = Args::parse(args, &[], &["-C"])?;
+    let (archive, paths) = match args.positional.split_first() {
+        Some(split) => split,
+        None => return usage("extract needs an archive"),
+    };
+    let dir = Path::new(args.options.get("-C").map_or(".", String::as_str));
+    std::fs::create_dir_all(dir)?;
+    let archive = read::Archive::from_path(archive)?;
+    if paths.is_empty() {
+        archive.release(dir)?;
+        return Ok(());
+    }
+    for path in paths {
+        let entry = archive
+            .entry(path)?
+            .ok_or_else(|| format!("{} isn't in the archive", path))?;
+        // Entries keep their path inside the archive, like tar does, the root's name included
+        let path = entry.path.trim_end_matches('/');
+        let out = dir.join(path);
+        let parent = out.parent().unwrap_or(dir);
+        std::fs::create_dir_all(parent)?;
+        if entry.is_file {
+            archive.extract_file(Path::new(path), &out)?;
+        } else {
+            archive.extract_file(Path::new(path), parent)?;
+        }
+    }
+    Ok(())
+}
+
+fn list(args: &[String]) -> CliResult {
+    let args = Args::parse(args, &["-l", "--json"], &[])?;
+    let archive = match args.positional.as_slice() {
+        [archive] => read::Archive::from_path(archive)?,
+        _ => return usage("list takes one archive"),
+    };
+    let entries = archive.entries()?;
+    if args.flag("--json") {
+        let entries = entries
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{{\"path\":{},\"type\":\"{}\",\"size\":{}}}",
+                    json_string(&entry.path),
+                    if entry.is_file { "file" } else { "directory" },
+                    entry.size
+                )
+            })
+            .collect::<Vec<_>>();
+        println!("[{}]", entries.join(","));
+    } else if args.flag("-l") {
+        for entry in entries {
+            if entry.is_file {
+                println!("{:>12} {}", entry.size, entry.path);
+            } else {
+                println!("{:>12} {}", "-", entry.path);
+            }
+        }
+    } else {
+        for entry in entries {
+            println!("{}", entry.path);
+        }
+    }
+    Ok(())
+}
+
+fn cat(args: &[String]) -> CliResult {
+    let args = Args::parse(args, &[], &[])?;
+    let (archive, path) = match args.positional.as_slice() {
+        [archive, path] => (read::Archive::from_path(archive)?, path),
+        _ => return usage("cat takes an archive and a path"),
+    };
+    let stdout = std::io::stdout();
+    match archive.extract_to(path, &mut stdout.lock()) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("no file at {}", path).into()),
+        // Piping into `head` isn't an error
+        Err(read::ReadError::IoError(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn inspect(args: &[String]) -> CliResult {
+    let args = Args::parse(args, &[], &[])?;
+    let mut archive = match args.positional.as_slice() {
+        [archive] => std::io::BufReader::new(std::fs::File::open(archive)?),
+        _ => return usage("inspect takes one archive"),
+    };
+    let stdout = std::io::stdout();
+    let found = read::inspect(&mut archive, &mut stdout.lock())?;
+    if !found.is_empty() {
+        return Err(format!("{} inconsistencies found", found.len()).into());
+    }
+    Ok(())
+}
+
+fn diff(args: &[String]) -> CliResult {
+    let args = Args::parse(args, &[], &[])?;
+    let (old, new) = match args.positional.as_slice() {
+        [old, new] => (read::Archive::from_path(old)?, Path::new(new)),
+        _ => return usage("diff takes two archives, or an archive and a directory"),
+    };
+    let diff = if new.is_dir() {
+        diff::archive_with_dir(&old, new)?
+    } else {
+        diff::archives(&old, &read::Archive::from_path(new)?)?
+    };
+    for change in &diff.changes {
+        println!("{}", change);
+    }
+    if !diff.is_empty() {
+        println!(
+            "{} changes, {:+} bytes",
+            diff.changes.len(),
+            diff.size_delta()
+        );
+    }
+    Ok(())
+}
+
+/// `s` as a JSON string literal
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,296 @@
+//! Helpers to embed an archive in a binary
+//!
+//! Pack a directory from the build script:
+//! ```ignore
+//! // build.rs
+//! fn main() {
+//!     klu_core::build::embed_dir("assets", "assets").expect("Unable to pack assets");
+//! }
+//! ```
+//! Then open it from the crate, without touching the filesystem:
+//! ```ignore
+//! let assets: klu_core::read::Archive = klu_core::include_archive!("assets");
+//! ```
+//!
+//! [generate_constants] also gives a constant per file, so a typo in a path doesn't compile:
+//! ```ignore
+//! // build.rs
+//! let archive = klu_core::build::embed_dir("assets", "assets").expect("Unable to pack assets");
+//! klu_core::build::generate_constants(archive, "assets").expect("Unable to generate constants");
+//! // main.rs
+//! include!(concat!(env!("OUT_DIR"), "/assets.rs"));
+//! let grass = assets.open_handle(&ASSETS::TEXTURES::GRASS_PNG);
+//! ```
+use crate::read::ReadResult;
+use crate::write::{Archive, PackOptions, WriteResult};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+fn out_dir() -> std::io::Result<PathBuf> {
+    std::env::var_os("OUT_DIR")
+        .map(PathBuf::from)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "OUT_DIR isn't set, this must be called from a build script",
+            )
+        })
+}
+
+/// Pack `dir` into `$OUT_DIR/<name>.klu`, to be embedded with [include_archive](crate::include_archive).
+/// Must be called from a build script, which will rerun whenever a packed file changes
+pub fn embed_dir<P: AsRef<Path>>(dir: P, name: &str) -> WriteResult<PathBuf> {
+    embed_dir_with(dir, name, &PackOptions::default())
+}
+
+/// Same as [embed_dir], only packing the entries accepted by `options`
+pub fn embed_dir_with<P: AsRef<Path>>(
+    dir: P,
+    name: &str,
+    options: &PackOptions,
+) -> WriteResult<PathBuf> {
+    let out_dir = out_dir()?;
+    let archive = Archive::from_path_with(dir, options)?;
+    // Directories are listed too, so adding or removing a file also triggers a rebuild
+    for path in archive.sources() {
+        println!("cargo:rerun-if-changed={}", path.display());
+    }
+    let out = out_dir.join(format!("{}.klu", name));
+    archive.write_to_path(&out)?;
+    Ok(out)
+}
+
+/// Write `$OUT_DIR/<name>.rs`, holding a [read::Handle](crate::read::Handle) constant for every
+/// file of the archive at `archive`. Must be called from a build script
+///
+/// Directories become nested modules, and names are uppercased with every character that can't
+/// be part of an identifier replaced by a `_`: `assets/textures/grass.png` gives
+/// `ASSETS::TEXTURES::GRASS_PNG`
+pub fn generate_constants<P: AsRef<Path>>(archive: P, name: &str) -> ReadResult<PathBuf> {
+    println!("cargo:rerun-if-changed={}", archive.as_ref().display());
+    let archive = crate::read::Archive::from_path(archive)?;
+    let out = out_dir()?.join(format!("{}.rs", name));
+    std::fs::write(&out, constants(&archive)?)?;
+    Ok(out)
+}
+
+/// Rust source of the constants written by [generate_constants]
+pub fn constants(archive: &crate::read::Archive) -> ReadResult<String> {
+    let entries = archive.entries()?;
+    let mut out = String::new();
+    // Names already used in every open module, the root's one included
+    let mut scopes = vec![std::collections::HashSet::new()];
+    // The top-level entries of an archive with several ones are at the root's depth
+    let top = if archive.root_name().is_empty() { 1 } else { 0 };
+    for entry in &entries {
+        let depth = entry.indices.len() - top;
+        while scopes.len() > depth + 1 {
+            scopes.pop();
+            writeln!(out, "{}}}", indent(scopes.len() - 1)).unwrap();
+        }
+        let name = entry.path.trim_end_matches('/').rsplit('/').next().unwrap();
+        let ident = unique_ident(name, scopes.last_mut().unwrap());
+        let pad = indent(depth);
+        writeln!(out, "{}/// `{}`", pad, entry.path).unwrap();
+        if depth == 0 {
+            writeln!(out, "#[allow(dead_code, non_snake_case)]").unwrap();
+        }
+        if !entry.is_file {
+            writeln!(out, "{}pub mod {} {{", pad, ident).unwrap();
+            scopes.push(std::collections::HashSet::new());
+            continue;
+        }
+        writeln!(
+            out,
+            "{}pub const {}: ::klu_core::read::Handle = ::klu_core::read::Handle {{ \
+             path: {:?}, size: {}, indices: &{:?} }};",
+            pad, ident, entry.path, entry.size, entry.indices
+        )
+        .unwrap();
+    }
+    while scopes.len() > 1 {
+        scopes.pop();
+        writeln!(out, "{}}}", indent(scopes.len() - 1)).unwrap();
+    }
+    Ok(out)
+}
+
+fn indent(depth: usize) -> String {
+    "    ".repeat(depth)
+}
+
+/// Turn `name` into an identifier not in `used`, and add it to `used`
+fn unique_ident(name: &str, used: &mut std::collections::HashSet<String>) -> String {
+    let mut ident = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    if !ident.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        ident.insert(0, '_');
+    }
+    // Keywords are lowercase but `Self`, so only `_` can't be used as it is
+    if ident == "_" {
+        ident.push('_');
+    }
+    let mut candidate = ident.clone();
+    let mut suffix = 1;
+    while used.contains(&candidate) {
+        suffix += 1;
+        candidate = format!("{}_{}", ident, suffix);
+    }
+    used.insert(candidate.clone());
+    candidate
+}
+
+/// Embed the archive packed by [build::embed_dir](crate::build::embed_dir) under `name`, and open
+/// it as a [read::Archive](crate::read::Archive)
+#[macro_export]
+macro_rules! include_archive {
+    ($name:expr) => {
+        $crate::read::Archive::from_bytes(include_bytes!(concat!(
+            env!("OUT_DIR"),
+            "/",
+            $name,
+            ".klu"
+        )) as &'static [u8])
+        .expect("The embedded archive isn't valid")
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The archive packed from `(path, content)` files below a root named `assets`, read back
+    /// from memory
+    fn archive(name: &str, files: &[(&str, &str)]) -> crate::read::Archive {
+        let dir = crate::scratch(&format!("build_{}", name));
+        for (file, content) in files {
+            let path = dir.join("assets").join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let out = dir.join("out.klu");
+        let options = PackOptions::new().reproducible(true);
+        Archive::from_path_with(dir.join("assets"), &options)
+            .unwrap()
+            .write_to_path(&out)
+            .unwrap();
+        let bytes = std::fs::read(&out).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        crate::read::Archive::from_bytes(bytes).unwrap()
+    }
+
+    fn idents(names: &[&str]) -> Vec<String> {
+        let mut used = std::collections::HashSet::new();
+        names
+            .iter()
+            .map(|name| unique_ident(name, &mut used))
+            .collect()
+    }
+
+    #[test]
+    fn mangling() {
+        let cases = [
+            ("grass.png", "GRASS_PNG"),
+            ("1st", "_1ST"),
+            ("_", "__"),
+            ("é.txt", "___TXT"),
+            ("sub dir", "SUB_DIR"),
+            ("match", "MATCH"),
+        ];
+        for (name, ident) in cases.iter().copied() {
+            assert_eq!(idents(&[name]), [ident]);
+        }
+        // Uppercasing never gives a keyword, `Self` being the only one with an uppercase letter
+        assert_eq!(idents(&["self", "Self"]), ["SELF", "SELF_2"]);
+    }
+
+    #[test]
+    fn collisions() {
+        assert_eq!(
+            idents(&["a-b", "a_b", "a.b", "A_B_2", "b"]),
+            ["A_B", "A_B_2", "A_B_3", "A_B_2_2", "B"]
+        );
+        // A suffix is never taken by an existing name
+        assert_eq!(idents(&["a_2", "a", "a"]), ["A_2", "A", "A_3"]);
+    }
+
+    #[test]
+    fn modules() {
+        let archive = archive(
+            "modules",
+            &[("sub/a", "a"), ("sub/A", "A"), ("sub.txt", "text")],
+        );
+        let expected = "\
+/// `assets/`
+#[allow(dead_code, non_snake_case)]
+pub mod ASSETS {
+    /// `assets/sub/`
+    pub mod SUB {
+        /// `assets/sub/A`
+        pub const A: ::klu_core::read::Handle = ::klu_core::read::Handle { \
+path: \"assets/sub/A\", size: 1, indices: &[0, 0] };
+        /// `assets/sub/a`
+        pub const A_2: ::klu_core::read::Handle = ::klu_core::read::Handle { \
+path: \"assets/sub/a\", size: 1, indices: &[0, 1] };
+    }
+    /// `assets/sub.txt`
+    pub const SUB_TXT: ::klu_core::read::Handle = ::klu_core::read::Handle { \
+path: \"assets/sub.txt\", size: 4, indices: &[1] };
+}
+";
+        assert_eq!(constants(&archive).unwrap(), expected);
+    }
+
+    #[test]
+    fn several_roots() {
+        use crate::write::File;
+        let root = File::directory(
+            "",
+            vec![
+                File::from_memory("readme", b"readme".to_vec()),
+                File::directory("docs", vec![File::from_memory("a", b"a".to_vec())]),
+            ],
+        );
+        let mut bytes = Vec::new();
+        Archive::from_file(root).write_to(&mut bytes).unwrap();
+        let archive = crate::read::Archive::from_bytes(bytes).unwrap();
+        let constants = constants(&archive).unwrap();
+        assert!(constants
+            .starts_with("/// `readme`\n#[allow(dead_code, non_snake_case)]\npub const README"));
+        assert!(constants.contains("\npub mod DOCS {\n"));
+        assert!(constants.contains("path: \"docs/a\", size: 1, indices: &[1, 0] };\n}\n"));
+    }
+
+    #[test]
+    fn open_generated_handle() {
+        let archive = archive("handle", &[("b/c", "second"), ("c", "first")]);
+        // The handle written for `assets/b/c`
+        let constants = constants(&archive).unwrap();
+        assert!(constants.contains("Handle { path: \"assets/b/c\", size: 6, indices: &[0, 0] }"));
+        let handle = crate::read::Handle {
+            path: "assets/b/c",
+            size: 6,
+            indices: &[0, 0],
+        };
+        assert_eq!(archive.handle_bytes(&handle), Some(&b"second"[..]));
+        #[cfg(feature = "virtual_fs")]
+        {
+            let mut file = archive.open_handle(&handle).unwrap();
+            assert_eq!(&*file.get_slice().unwrap(), b"second");
+        }
+        // A handle generated for another archive
+        let stale = crate::read::Handle {
+            path: "assets/b/d",
+            ..handle
+        };
+        assert_eq!(archive.handle_bytes(&stale), None);
+    }
+}
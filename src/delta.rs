@@ -0,0 +1,720 @@
+//! Binary patches turning an archive into a newer version of it
+//!
+//! ```ignore
+//! let old = klu_core::read::Archive::from_path("v1.klu")?;
+//! let new = klu_core::read::Archive::from_path("v2.klu")?;
+//! klu_core::delta::create(&old, &new)?.write_to_path("v1-v2.klu")?;
+//! // On the user's side
+//! let patch = klu_core::read::Archive::from_path("v1-v2.klu")?;
+//! klu_core::delta::apply_to_path(&old, &patch, "v2.klu")?;
+//! ```
+//! A patch is itself an archive, holding a `patch/` directory:
+//! - `manifest`: the length (u64) and SHA-256 of the old archive, then of the new one
+//! - `ops`: how to rebuild the new archive, each op being either `0`, an offset and a length
+//!   (u64) to copy from the old archive, or `1` and a length to take from `literals`
+//! - `literals`: the bytes found nowhere in the old archive, one op after the other
+//!
+//! The data of every file of the new archive is taken from the old file with the same content if
+//! there is one, else diffed against the old file with the same path, by blocks. Applying a patch
+//! checks that it is given the archive it was made from, and that it rebuilt the new one byte for
+//! byte. Both archives are read by chunks, only the literals are kept in memory
+use crate::hash::{Fnv, Sha256};
+use crate::read::{self, ReadError};
+use crate::write::{self, WriteError};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+
+/// Result type used by patches
+pub type DeltaResult<T> = Result<T, DeltaError>;
+
+#[derive(Debug)]
+/// Error creating or applying a patch
+pub enum DeltaError {
+    /// An IO Error
+    IoError(std::io::Error),
+    /// One of the archives can't be read
+    ReadError(ReadError),
+    /// The patch can't be written
+    WriteError(WriteError),
+    /// The patch isn't valid
+    InvalidPatch(String),
+    /// The patch was made for another archive
+    WrongBase,
+    /// The rebuilt archive isn't the one the patch was made from
+    ChecksumMismatch,
+}
+
+impl From<std::io::Error> for DeltaError {
+    fn from(err: std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl From<ReadError> for DeltaError {
+    fn from(err: ReadError) -> Self {
+        Self::ReadError(err)
+    }
+}
+
+impl From<WriteError> for DeltaError {
+    fn from(err: WriteError) -> Self {
+        Self::WriteError(err)
+    }
+}
+
+impl std::error::Error for DeltaError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IoError(e) => Some(e),
+            Self::ReadError(e) => Some(e),
+            Self::WriteError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for DeltaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::IoError(e) => write!(f, "{}", e),
+            Self::ReadError(e) => write!(f, "{}", e),
+            Self::WriteError(e) => write!(f, "{}", e),
+            Self::InvalidPatch(context) => write!(f, "Invalid patch: {}", context),
+            Self::WrongBase => write!(f, "The patch was made for another archive"),
+            Self::ChecksumMismatch => write!(f, "The patched archive doesn't match its checksum"),
+        }
+    }
+}
+
+const OP_COPY: u8 = 0;
+const OP_LITERAL: u8 = 1;
+
+/// Size of the chunks archives are read by
+const CHUNK: usize = 64 * 1024;
+
+/// The ops of a patch being built
+#[derive(Default)]
+struct Ops {
+    ops: Vec<u8>,
+    literals: Vec<u8>,
+    /// The last op, kept to be merged with the next one if contiguous
+    pending: Option<(u8, u64, u64)>,
+}
+
+impl Ops {
+    fn copy(&mut self, offset: u64, length: u64) {
+        if length == 0 {
+            return;
+        }
+        match &mut self.pending {
+            Some((OP_COPY, start, len)) if *start + *len == offset => *len += length,
+            _ => self.push((OP_COPY, offset, length)),
+        }
+    }
+
+    fn literal(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.literals.extend_from_slice(data);
+        match &mut self.pending {
+            Some((OP_LITERAL, _, len)) => *len += data.len() as u64,
+            _ => self.push((OP_LITERAL, 0, data.len() as u64)),
+        }
+    }
+
+    /// Take the `length` bytes at `offset` in `archive` as they are
+    fn literal_range(
+        &mut self,
+        archive: &read::Archive,
+        offset: u64,
+        length: u64,
+    ) -> DeltaResult<()> {
+        let mut window = Window::new(archive, offset, length);
+        let mut position = 0;
+        while position < length {
+            let chunk = window.get(position, position + CHUNK as u64)?;
+            self.literal(chunk);
+            position += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, op: (u8, u64, u64)) {
+        self.flush();
+        self.pending = Some(op);
+    }
+
+    fn flush(&mut self) {
+        match self.pending.take() {
+            Some((OP_COPY, offset, length)) => {
+                self.ops.push(OP_COPY);
+                self.ops.extend_from_slice(&offset.to_be_bytes());
+                self.ops.extend_from_slice(&length.to_be_bytes());
+            }
+            Some((_, _, length)) => {
+                self.ops.push(OP_LITERAL);
+                self.ops.extend_from_slice(&length.to_be_bytes());
+            }
+            None => {}
+        }
+    }
+}
+
+/// Length of the manifest, a length and a SHA-256 for both archives
+const MANIFEST: u64 = 2 * (8 + 32);
+
+/// A range of an archive read by chunks, keeping only the bytes still asked for
+struct Window<'a> {
+    archive: &'a read::Archive,
+    /// Absolute offset of the range
+    offset: u64,
+    length: u64,
+    /// Position in the range of the first byte of `buffer`
+    start: u64,
+    buffer: Vec<u8>,
+}
+
+impl<'a> Window<'a> {
+    fn new(archive: &'a read::Archive, offset: u64, length: u64) -> Self {
+        Window {
+            archive,
+            offset,
+            length,
+            start: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// The bytes of the range from `from` to `to`, or to its end. The bytes before `from` are
+    /// dropped, so `from` can't go back
+    fn get(&mut self, from: u64, to: u64) -> DeltaResult<&[u8]> {
+        let to = to.min(self.length);
+        debug_assert!(self.start <= from && from <= to);
+        let end = self.start + self.buffer.len() as u64;
+        if to > end {
+            let keep = from.min(end);
+            self.buffer.drain(..(keep - self.start) as usize);
+            self.start = keep;
+            // At least a chunk, so a window sliding by one byte doesn't read by one byte
+            let n = (to - end).max(CHUNK as u64).min(self.length - end) as usize;
+            let filled = self.buffer.len();
+            self.buffer.resize(filled + n, 0);
+            self.archive
+                .read_raw(&mut self.buffer[filled..], self.offset + end)?;
+        }
+        Ok(&self.buffer[(from - self.start) as usize..(to - self.start) as usize])
+    }
+}
+
+/// SHA-256 of `length` bytes at `offset` in `archive`
+fn hash_range(archive: &read::Archive, offset: u64, length: u64) -> DeltaResult<[u8; 32]> {
+    let mut hash = Sha256::new();
+    let mut buffer = vec![0; CHUNK];
+    let mut position = 0;
+    while position < length {
+        let n = (length - position).min(CHUNK as u64) as usize;
+        archive.read_raw(&mut buffer[..n], offset + position)?;
+        hash.update(&buffer[..n]);
+        position += n as u64;
+    }
+    Ok(hash.finish())
+}
+
+/// Length and checksum of the whole archive
+fn checksum(archive: &read::Archive) -> DeltaResult<(u64, [u8; 32])> {
+    let len = archive.raw_len()?;
+    Ok((len, hash_range(archive, 0, len)?))
+}
+
+fn fnv(data: &[u8]) -> u64 {
+    let mut hash = Fnv::new();
+    hash.update(data);
+    hash.finish()
+}
+
+/// The big-endian u64 at the start of `bytes`
+fn be_u64(bytes: &[u8]) -> u64 {
+    let mut number = [0; 8];
+    number.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(number)
+}
+
+/// Path of a file below the root, so renaming the root doesn't change it. The top-level entries
+/// of an archive whose root has no name are told apart by their own names, so they are kept
+fn relative<'p>(archive: &read::Archive, path: &'p str) -> &'p str {
+    if archive.root_name().is_empty() {
+        return path;
+    }
+    path.split_once('/').map_or(path, |(_, relative)| relative)
+}
+
+/// Create the patch turning `old` into `new`, to be written with [write::Archive::write_to_path]
+pub fn create(old: &read::Archive, new: &read::Archive) -> DeltaResult<write::Archive> {
+    let (old_len, old_checksum) = checksum(old)?;
+    let (new_len, new_checksum) = checksum(new)?;
+    let mut by_path = HashMap::new();
+    let mut by_content = HashMap::new();
+    for (path, offset, length) in old.data_ranges()? {
+        by_content.insert((length, hash_range(old, offset, length)?), offset);
+        by_path.insert(relative(old, &path).to_owned(), (offset, length));
+    }
+
+    let mut ops = Ops::default();
+    let mut position = 0;
+    for (path, offset, length) in new.data_ranges()? {
+        let headers = offset.checked_sub(position).ok_or_else(|| {
+            ReadError::InvalidArchive(format!("data of {} overlaps the previous file", path))
+        })?;
+        // Registrations and directory headers, small enough to be stored as they are
+        ops.literal_range(new, position, headers)?;
+        if let Some(old_offset) = by_content.get(&(length, hash_range(new, offset, length)?)) {
+            ops.copy(*old_offset, length);
+        } else if let Some(old_range) = by_path.get(relative(new, &path)) {
+            diff_blocks(old, *old_range, new, (offset, length), &mut ops)?;
+        } else {
+            ops.literal_range(new, offset, length)?;
+        }
+        position = offset + length;
+    }
+    let end = new_len.checked_sub(position).ok_or_else(|| {
+        ReadError::InvalidArchive("file data goes past the end of the archive".to_owned())
+    })?;
+    ops.literal_range(new, position, end)?;
+    ops.flush();
+
+    let mut manifest = Vec::new();
+    for (len, checksum) in &[(old_len, old_checksum), (new_len, new_checksum)] {
+        manifest.extend_from_slice(&len.to_be_bytes());
+        manifest.extend_from_slice(checksum);
+    }
+    let root = write::File::directory(
+        "patch",
+        vec![
+            write::File::from_memory("manifest", manifest),
+            write::File::from_memory("ops", ops.ops),
+            write::File::from_memory("literals", ops.literals),
+        ],
+    );
+    Ok(write::Archive::from_file(root))
+}
+
+/// Rolling checksum of a block, a sum of its bytes and a sum of these sums
+#[derive(Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let mut rolling = Rolling { a: 0, b: 0 };
+        for byte in block {
+            rolling.a = rolling.a.wrapping_add(u32::from(*byte));
+            rolling.b = rolling.b.wrapping_add(rolling.a);
+        }
+        rolling
+    }
+
+    /// Slide the block of `size` bytes by one, dropping `out` and taking `inside`
+    fn roll(&mut self, out: u8, inside: u8, size: usize) {
+        self.a = self
+            .a
+            .wrapping_sub(u32::from(out))
+            .wrapping_add(u32::from(inside));
+        self.b = self
+            .b
+            .wrapping_sub((size as u32).wrapping_mul(u32::from(out)))
+            .wrapping_add(self.a);
+    }
+
+    fn value(&self) -> u32 {
+        (self.b << 16) | (self.a & 0xffff)
+    }
+}
+
+/// Push the ops building the data at `new_range` in `new` out of the data at `old_range` in `old`,
+/// both ranges being an offset and a length
+fn diff_blocks(
+    old: &read::Archive,
+    (old_offset, old_len): (u64, u64),
+    new: &read::Archive,
+    (new_offset, new_len): (u64, u64),
+    ops: &mut Ops,
+) -> DeltaResult<()> {
+    // Bigger files get bigger blocks, so their index stays small
+    let size = ((old_len as f64).sqrt() as u64).max(64);
+    if new_len < size || old_len < size {
+        return ops.literal_range(new, new_offset, new_len);
+    }
+    // Starts of the old blocks by rolling checksum, along with the hash of their bytes
+    let mut blocks = HashMap::<u32, Vec<(u64, u64)>>::new();
+    let mut old_window = Window::new(old, old_offset, old_len);
+    let mut start = 0;
+    while start + size <= old_len {
+        let block = old_window.get(start, start + size)?;
+        blocks
+            .entry(Rolling::new(block).value())
+            .or_default()
+            .push((start, fnv(block)));
+        start += size;
+    }
+
+    let mut new_window = Window::new(new, new_offset, new_len);
+    let mut block = vec![0; size as usize];
+    let mut buffer = vec![0; CHUNK];
+    let mut literal_start = 0;
+    let mut i = 0;
+    let mut rolling = Rolling::new(new_window.get(0, size)?);
+    while i + size <= new_len {
+        // Bytes found nowhere are pushed as they go, so the window doesn't grow with them
+        if i - literal_start >= CHUNK as u64 {
+            ops.literal(new_window.get(literal_start, i)?);
+            literal_start = i;
+        }
+        let window = new_window.get(literal_start, i + size + 1)?;
+        let at = (i - literal_start) as usize;
+        let current = &window[at..at + size as usize];
+        let (out, inside) = (current[0], window.get(at + size as usize).copied());
+        let mut found = None;
+        if let Some(starts) = blocks.get(&rolling.value()) {
+            let hash = fnv(current);
+            // Checked, as two blocks can share both sums
+            for (start, _) in starts.iter().filter(|(_, h)| *h == hash) {
+                old.read_raw(&mut block, old_offset + start)?;
+                if block[..] == *current {
+                    found = Some(*start);
+                    break;
+                }
+            }
+        }
+        if let Some(start) = found {
+            ops.literal(new_window.get(literal_start, i)?);
+            // Extend the match past the block, it may go on up to the next change
+            let mut length = size;
+            loop {
+                let n = (old_len - start - length)
+                    .min(new_len - i - length)
+                    .min(CHUNK as u64);
+                if n == 0 {
+                    break;
+                }
+                let old_chunk = &mut buffer[..n as usize];
+                old.read_raw(old_chunk, old_offset + start + length)?;
+                let same = new_window
+                    .get(i + length, i + length + n)?
+                    .iter()
+                    .zip(old_chunk.iter())
+                    .take_while(|(a, b)| a == b)
+                    .count() as u64;
+                length += same;
+                if same < n {
+                    break;
+                }
+            }
+            ops.copy(old_offset + start, length);
+            i += length;
+            literal_start = i;
+            if i + size <= new_len {
+                rolling = Rolling::new(new_window.get(i, i + size)?);
+            }
+        } else {
+            if let Some(inside) = inside {
+                rolling.roll(out, inside, size as usize);
+            }
+            i += 1;
+        }
+    }
+    while literal_start < new_len {
+        let chunk = new_window.get(literal_start, literal_start + CHUNK as u64)?;
+        ops.literal(chunk);
+        literal_start += chunk.len() as u64;
+    }
+    Ok(())
+}
+
+/// Rebuild the new archive out of `old` and `patch`, writing it to `out`
+///
+/// `out` has received the data when a [DeltaError::ChecksumMismatch] is found, see
+/// [apply_to_path] which removes the file
+pub fn apply<W: Write>(old: &read::Archive, patch: &read::Archive, out: &mut W) -> DeltaResult<()> {
+    let range = |name: &str| {
+        patch
+            .data_range(Path::new("patch").join(name))
+            .ok_or_else(|| DeltaError::InvalidPatch(format!("no {} file", name)))
+    };
+    let (manifest, ops, literals) = (range("manifest")?, range("ops")?, range("literals")?);
+    if manifest.1 != MANIFEST {
+        return Err(DeltaError::InvalidPatch(format!(
+            "manifest isn't {} bytes",
+            MANIFEST
+        )));
+    }
+    let mut bytes = [0; MANIFEST as usize];
+    patch.read_raw(&mut bytes, manifest.0)?;
+    let (old_len, new_len) = (be_u64(&bytes), be_u64(&bytes[40..]));
+    let (old_checksum, new_checksum) = (&bytes[8..40], &bytes[48..]);
+    let (len, checksum) = checksum(old)?;
+    if (len, &checksum[..]) != (old_len, old_checksum) {
+        return Err(DeltaError::WrongBase);
+    }
+
+    let (mut ops, ops_len) = (Window::new(patch, ops.0, ops.1), ops.1);
+    let (mut literal, literals_end) = (literals.0, literals.0 + literals.1);
+    let mut hash = Sha256::new();
+    let mut written = 0_u64;
+    let mut buffer = vec![0; CHUNK];
+    let mut pos = 0;
+    while pos < ops_len {
+        let op = ops.get(pos, pos + 17)?;
+        let size = if op[0] == OP_COPY { 17 } else { 9 };
+        if op.len() < size || op[0] > OP_LITERAL {
+            return Err(DeltaError::InvalidPatch(format!("bad op at {}", pos)));
+        }
+        let (archive, mut offset, length) = if op[0] == OP_COPY {
+            let (offset, length) = (be_u64(&op[1..]), be_u64(&op[9..]));
+            if offset.checked_add(length).is_none_or(|end| end > old_len) {
+                return Err(DeltaError::InvalidPatch(format!(
+                    "copy past the end at {}",
+                    pos
+                )));
+            }
+            (old, offset, length)
+        } else {
+            let length = be_u64(&op[1..]);
+            if literal
+                .checked_add(length)
+                .is_none_or(|end| end > literals_end)
+            {
+                return Err(DeltaError::InvalidPatch(format!(
+                    "literal past the end at {}",
+                    pos
+                )));
+            }
+            literal += length;
+            (patch, literal - length, length)
+        };
+        let end = offset + length;
+        while offset < end {
+            let n = (end - offset).min(CHUNK as u64) as usize;
+            archive.read_raw(&mut buffer[..n], offset)?;
+            hash.update(&buffer[..n]);
+            out.write_all(&buffer[..n])?;
+            offset += n as u64;
+        }
+        written += length;
+        pos += size as u64;
+    }
+    out.flush()?;
+    if written != new_len || hash.finish()[..] != *new_checksum {
+        return Err(DeltaError::ChecksumMismatch);
+    }
+    Ok(())
+}
+
+/// Same as [apply], writing the new archive at `path`, which is removed if anything fails
+pub fn apply_to_path<P: AsRef<Path>>(
+    old: &read::Archive,
+    patch: &read::Archive,
+    path: P,
+) -> DeltaResult<()> {
+    let mut out = std::io::BufWriter::new(std::fs::File::create(&path)?);
+    let result = apply(old, patch, &mut out);
+    drop(out);
+    if result.is_err() {
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write::File;
+
+    fn bytes(root: File) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write::Archive::from_file(root)
+            .write_to(&mut bytes)
+            .unwrap();
+        bytes
+    }
+
+    fn archive(root: File) -> read::Archive {
+        read::Archive::from_bytes(bytes(root)).unwrap()
+    }
+
+    fn patch(old: &read::Archive, new: &read::Archive) -> read::Archive {
+        let mut bytes = Vec::new();
+        create(old, new).unwrap().write_to(&mut bytes).unwrap();
+        read::Archive::from_bytes(bytes).unwrap()
+    }
+
+    fn applied(old: &read::Archive, patch: &read::Archive) -> DeltaResult<Vec<u8>> {
+        let mut out = Vec::new();
+        apply(old, patch, &mut out)?;
+        Ok(out)
+    }
+
+    /// `len` bytes that don't repeat, different for each `seed`
+    fn noise(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn literals(patch: &read::Archive) -> usize {
+        patch.bytes("patch/literals").unwrap().len()
+    }
+
+    #[test]
+    fn round_trip() {
+        let big = noise(1, 300_000);
+        let mut edited = big.clone();
+        edited.splice(1000..1010, b"inserted in the middle".iter().copied());
+        edited[200_000] ^= 1;
+        let old = archive(File::directory(
+            "v1",
+            vec![
+                File::from_memory("big", big),
+                File::from_memory("moved", noise(2, 50_000)),
+                File::from_memory("removed", noise(3, 10_000)),
+            ],
+        ));
+        let new_bytes = bytes(File::directory(
+            "v2",
+            vec![
+                File::from_memory("added", b"new file".to_vec()),
+                File::from_memory("big", edited),
+                File::directory("sub", vec![File::from_memory("moved", noise(2, 50_000))]),
+            ],
+        ));
+        let new = read::Archive::from_bytes(new_bytes.clone()).unwrap();
+        let patch = patch(&old, &new);
+        assert_eq!(applied(&old, &patch).unwrap(), new_bytes);
+        // The two edits of `big`, headers and the added file
+        assert!(literals(&patch) < 4096, "{} bytes", literals(&patch));
+        // Only the registrations of an unchanged archive are stored
+        assert!(literals(&self::patch(&new, &new)) < 256);
+    }
+
+    #[test]
+    fn several_roots() {
+        let (a, b) = (noise(4, 100_000), noise(5, 100_000));
+        let root = |a: &[u8], b: &[u8]| {
+            File::directory(
+                "",
+                vec![
+                    File::directory("a", vec![File::from_memory("data", a.to_vec())]),
+                    File::directory("b", vec![File::from_memory("data", b.to_vec())]),
+                ],
+            )
+        };
+        let old = archive(root(&a, &b));
+        let (mut a2, mut b2) = (a, b);
+        a2[10] ^= 1;
+        b2[50_000] ^= 1;
+        let new_bytes = bytes(root(&a2, &b2));
+        let new = read::Archive::from_bytes(new_bytes.clone()).unwrap();
+        let patch = patch(&old, &new);
+        assert_eq!(applied(&old, &patch).unwrap(), new_bytes);
+        // Each `data` is diffed against the one of its own top-level directory
+        assert!(literals(&patch) < 4096, "{} bytes", literals(&patch));
+    }
+
+    #[test]
+    fn wrong_base() {
+        let old = archive(File::directory(
+            "r",
+            vec![File::from_memory("f", b"1234".to_vec())],
+        ));
+        let new = archive(File::directory(
+            "r",
+            vec![File::from_memory("f", b"5678".to_vec())],
+        ));
+        let patch = patch(&old, &new);
+        // Same length, other bytes
+        let other = archive(File::directory(
+            "r",
+            vec![File::from_memory("f", b"abcd".to_vec())],
+        ));
+        assert!(matches!(
+            applied(&other, &patch),
+            Err(DeltaError::WrongBase)
+        ));
+        assert!(matches!(applied(&new, &patch), Err(DeltaError::WrongBase)));
+    }
+
+    #[test]
+    fn corrupted_patch() {
+        let old = archive(File::directory(
+            "r",
+            vec![File::from_memory("f", noise(6, 10_000))],
+        ));
+        let mut content = noise(6, 10_000);
+        content[5000] ^= 1;
+        let new = archive(File::directory("r", vec![File::from_memory("f", content)]));
+        let patch = patch(&old, &new);
+        let file = |name: &str| patch.bytes(format!("patch/{}", name)).unwrap().to_vec();
+        let rebuilt = |ops: Vec<u8>, literals: Vec<u8>| {
+            archive(File::directory(
+                "patch",
+                vec![
+                    File::from_memory("manifest", file("manifest")),
+                    File::from_memory("ops", ops),
+                    File::from_memory("literals", literals),
+                ],
+            ))
+        };
+        assert!(applied(&old, &rebuilt(file("ops"), file("literals"))).is_ok());
+
+        let mut literals = file("literals");
+        *literals.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            applied(&old, &rebuilt(file("ops"), literals)),
+            Err(DeltaError::ChecksumMismatch)
+        ));
+        let mut ops = file("ops");
+        ops.pop();
+        assert!(matches!(
+            applied(&old, &rebuilt(ops, file("literals"))),
+            Err(DeltaError::InvalidPatch(_))
+        ));
+        let mut ops = file("ops");
+        ops.extend_from_slice(&[OP_COPY, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(matches!(
+            applied(&old, &rebuilt(ops, file("literals"))),
+            Err(DeltaError::InvalidPatch(_))
+        ));
+        let mut ops = file("ops");
+        ops.extend_from_slice(&[OP_LITERAL, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert!(matches!(
+            applied(&old, &rebuilt(ops, file("literals"))),
+            Err(DeltaError::InvalidPatch(_))
+        ));
+        let missing = archive(File::directory(
+            "patch",
+            vec![File::from_memory("manifest", file("manifest"))],
+        ));
+        assert!(matches!(
+            applied(&old, &missing),
+            Err(DeltaError::InvalidPatch(_))
+        ));
+
+        // The file written by a failed patch doesn't stay around
+        let dir = crate::scratch("delta_corrupted_patch");
+        let path = dir.join("patched.klu");
+        let mut literals = file("literals");
+        literals[0] ^= 1;
+        assert!(apply_to_path(&old, &rebuilt(file("ops"), literals), &path).is_err());
+        assert!(!path.exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
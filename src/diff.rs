@@ -0,0 +1,510 @@
+//! Compare two archives, or an archive against a directory
+//!
+//! ```ignore
+//! let old = klu_core::read::Archive::from_path("v1.klu")?;
+//! let new = klu_core::read::Archive::from_path("v2.klu")?;
+//! for change in klu_core::diff::archives(&old, &new)?.changes {
+//!     println!("{}", change);
+//! }
+//! ```
+//! Paths are compared below the roots, so trees packed from differently named directories can be
+//! compared. A file moved without being modified is recognized by its content
+use crate::hash::Fnv;
+use crate::read::{Archive, ReadError, ReadResult};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A difference between the old and the new tree
+///
+/// Paths are relative to the roots, directories ending with a `/`. Directories are only ever
+/// added or removed, and their size is 0
+pub enum Change {
+    /// An entry only found in the new tree
+    Added {
+        /// Path of the entry
+        path: String,
+        /// Size of the entry
+        size: u64,
+    },
+    /// An entry only found in the old tree
+    Removed {
+        /// Path of the entry
+        path: String,
+        /// Size of the entry
+        size: u64,
+    },
+    /// A file moved to another path, its content unchanged
+    Renamed {
+        /// Path in the old tree
+        from: String,
+        /// Path in the new tree
+        to: String,
+        /// Size of the file
+        size: u64,
+    },
+    /// A file whose content changed
+    Modified {
+        /// Path of the file
+        path: String,
+        /// Size in the old tree
+        old_size: u64,
+        /// Size in the new tree
+        new_size: u64,
+    },
+}
+
+impl Change {
+    /// Path of the entry, in the new tree unless it was removed
+    pub fn path(&self) -> &str {
+        match self {
+            Self::Added { path, .. } | Self::Removed { path, .. } | Self::Modified { path, .. } => {
+                path
+            }
+            Self::Renamed { to, .. } => to,
+        }
+    }
+
+    /// How many bytes the change adds, negative if it removes some
+    pub fn size_delta(&self) -> i128 {
+        match self {
+            Self::Added { size, .. } => i128::from(*size),
+            Self::Removed { size, .. } => -i128::from(*size),
+            Self::Renamed { .. } => 0,
+            Self::Modified {
+                old_size, new_size, ..
+            } => i128::from(*new_size) - i128::from(*old_size),
+        }
+    }
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Added { path, size } => write!(f, "added    {} (+{})", path, size),
+            Self::Removed { path, size } => write!(f, "removed  {} (-{})", path, size),
+            Self::Renamed { from, to, .. } => write!(f, "renamed  {} -> {}", from, to),
+            Self::Modified {
+                path,
+                old_size,
+                new_size,
+            } => write!(
+                f,
+                "modified {} ({} -> {}, {:+})",
+                path,
+                old_size,
+                new_size,
+                self.size_delta()
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Every difference between two trees, sorted by path
+pub struct Diff {
+    /// The changes, see [Change::path] for their order
+    pub changes: Vec<Change>,
+}
+
+impl Diff {
+    /// Returns true if both trees are the same
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// How many bytes the new tree's entries hold more than the old one's
+    pub fn size_delta(&self) -> i128 {
+        self.changes.iter().map(Change::size_delta).sum()
+    }
+}
+
+/// Compare the `old` archive to the `new` one
+pub fn archives(old: &Archive, new: &Archive) -> ReadResult<Diff> {
+    diff(&Tree::archive(old)?, &Tree::archive(new)?)
+}
+
+/// Compare the `old` archive to the directory `new`, as it would be packed
+pub fn archive_with_dir<P: AsRef<Path>>(old: &Archive, new: P) -> ReadResult<Diff> {
+    diff(&Tree::archive(old)?, &Tree::Dir(new.as_ref().to_path_buf()))
+}
+
+/// An entry of a [Tree]
+struct Item {
+    is_file: bool,
+    size: u64,
+}
+
+/// One side of the comparison
+enum Tree<'a> {
+    /// An archive, with the path of its root to prefix relative paths with
+    Archive(&'a Archive, String),
+    Dir(PathBuf),
+}
+
+impl<'a> Tree<'a> {
+    fn archive(archive: &'a Archive) -> ReadResult<Self> {
+        // Several top-level entries are compared as they are
+        if archive.root_name().is_empty() {
+            return Ok(Tree::Archive(archive, String::new()));
+        }
+        let root = archive
+            .entries()?
+            .into_iter()
+            .next()
+            .map(|root| root.path)
+            .filter(|path| path.ends_with('/'))
+            .unwrap_or_default();
+        Ok(Tree::Archive(archive, root))
+    }
+
+    /// Every entry below the root, by relative path
+    fn items(&self) -> ReadResult<BTreeMap<String, Item>> {
+        let mut items = BTreeMap::new();
+        match self {
+            Self::Archive(archive, root) => {
+                for entry in archive.entries()? {
+                    match entry.path.strip_prefix(root.as_str()) {
+                        Some(path) if !path.is_empty() => {
+                            let item = Item {
+                                is_file: entry.is_file,
+                                size: entry.size,
+                            };
+                            items.insert(path.to_owned(), item);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            Self::Dir(dir) => walk(dir, "", &mut items)?,
+        }
+        Ok(items)
+    }
+
+    /// A reader over the content of the file at `path`
+    fn open(&self, path: &str) -> ReadResult<Box<dyn Read + '_>> {
+        match self {
+            Self::Archive(archive, root) => archive
+                .data_reader(format!("{}{}", root, path))?
+                .map(|reader| Box::new(reader) as Box<dyn Read>)
+                .ok_or_else(|| {
+                    ReadError::IoError(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("no file at {}", path),
+                    ))
+                }),
+            Self::Dir(dir) => Ok(Box::new(std::fs::File::open(dir.join(path))?)),
+        }
+    }
+
+    /// Hash of the content of the file at `path`, to find the files that may be the same
+    fn hash(&self, path: &str) -> ReadResult<u64> {
+        let mut hash = Fnv::new();
+        match self {
+            Self::Archive(archive, root) => {
+                archive.extract_to(format!("{}{}", root, path), &mut hash)?;
+            }
+            Self::Dir(dir) => {
+                std::io::copy(&mut std::fs::File::open(dir.join(path))?, &mut hash)?;
+            }
+        }
+        Ok(hash.finish())
+    }
+}
+
+/// Returns true if the file at `path` in `old` holds the same bytes as the one at `new_path` in
+/// `new`
+fn same_content(old: &Tree, path: &str, new: &Tree, new_path: &str) -> ReadResult<bool> {
+    let (mut old, mut new) = (old.open(path)?, new.open(new_path)?);
+    let (mut a, mut b) = (vec![0; 64 * 1024], vec![0; 64 * 1024]);
+    loop {
+        let n = fill(&mut old, &mut a)?;
+        if n != fill(&mut new, &mut b)? || a[..n] != b[..n] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Read into `buf` until it is full or `reader` ends, returns how many bytes were read
+fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Push the entries inside `dir`, found at `relative` from the root, onto `items`
+fn walk(dir: &Path, relative: &str, items: &mut BTreeMap<String, Item>) -> ReadResult<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        // Links are followed, as when packing
+        let metadata = std::fs::metadata(entry.path())?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if metadata.is_dir() {
+            let path = format!("{}{}/", relative, name);
+            walk(&entry.path(), &path, items)?;
+            let item = Item {
+                is_file: false,
+                size: 0,
+            };
+            items.insert(path, item);
+        } else {
+            let item = Item {
+                is_file: true,
+                size: metadata.len(),
+            };
+            items.insert(format!("{}{}", relative, name), item);
+        }
+    }
+    Ok(())
+}
+
+fn diff(old: &Tree, new: &Tree) -> ReadResult<Diff> {
+    let old_items = old.items()?;
+    let new_items = new.items()?;
+    let mut changes = Vec::new();
+    let mut removed = Vec::new();
+    for (path, o) in &old_items {
+        match new_items.get(path) {
+            // Directories' paths end with a `/`, so both are files or both are directories
+            Some(n) if n.is_file => {
+                if o.size != n.size || !same_content(old, path, new, path)? {
+                    changes.push(Change::Modified {
+                        path: path.clone(),
+                        old_size: o.size,
+                        new_size: n.size,
+                    });
+                }
+            }
+            Some(_) => {}
+            None => removed.push((path, o)),
+        }
+    }
+    // Added files, by size and hash, to be matched with the removed ones holding the same bytes
+    let mut added = HashMap::<_, Vec<&String>>::new();
+    for (path, n) in &new_items {
+        if old_items.contains_key(path) {
+            continue;
+        }
+        if n.is_file {
+            let key = (n.size, new.hash(path)?);
+            added.entry(key).or_default().push(path);
+        } else {
+            changes.push(Change::Added {
+                path: path.clone(),
+                size: n.size,
+            });
+        }
+    }
+    for (path, o) in removed {
+        let mut to = None;
+        if o.is_file {
+            if let Some(candidates) = added.get_mut(&(o.size, old.hash(path)?)) {
+                for i in (0..candidates.len()).rev() {
+                    if same_content(old, path, new, candidates[i])? {
+                        to = Some(candidates.remove(i));
+                        break;
+                    }
+                }
+            }
+        }
+        changes.push(match to {
+            Some(to) => Change::Renamed {
+                from: path.clone(),
+                to: to.clone(),
+                size: o.size,
+            },
+            None => Change::Removed {
+                path: path.clone(),
+                size: o.size,
+            },
+        });
+    }
+    for ((size, _), paths) in added {
+        for path in paths {
+            changes.push(Change::Added {
+                path: path.clone(),
+                size,
+            });
+        }
+    }
+    changes.sort_by(|a, b| a.path().cmp(b.path()));
+    Ok(Diff { changes })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write::PackOptions;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// The archive packed from `(path, content)` files below a root named `root`, read back from
+    /// memory
+    fn archive(root: &str, files: &[(&str, &[u8])]) -> Archive {
+        // Tests build their archives concurrently
+        static PACKED: AtomicUsize = AtomicUsize::new(0);
+        let n = PACKED.fetch_add(1, Ordering::Relaxed);
+        let dir = crate::scratch(&format!("diff_{}_{}", root, n));
+        for (file, content) in files {
+            let path = dir.join(root).join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let out = dir.join("out.klu");
+        let options = PackOptions::new().reproducible(true);
+        crate::write::Archive::from_path_with(dir.join(root), &options)
+            .unwrap()
+            .write_to_path(&out)
+            .unwrap();
+        let bytes = std::fs::read(&out).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        Archive::from_bytes(bytes).unwrap()
+    }
+
+    fn old() -> Archive {
+        archive(
+            "old",
+            &[
+                ("same", b"unchanged"),
+                ("edited", b"abcd"),
+                ("grown", b"abc"),
+                ("gone", b"removed"),
+                ("moved", b"moved content"),
+                ("kind", b"file then directory"),
+                ("dir/inner", b"y"),
+            ],
+        )
+    }
+
+    /// The changes expected from [old] to the new tree, whatever its root is named
+    fn expected() -> Vec<Change> {
+        vec![
+            Change::Added {
+                path: "added".to_owned(),
+                size: 3,
+            },
+            Change::Removed {
+                path: "dir/".to_owned(),
+                size: 0,
+            },
+            Change::Removed {
+                path: "dir/inner".to_owned(),
+                size: 1,
+            },
+            // Same size, other bytes
+            Change::Modified {
+                path: "edited".to_owned(),
+                old_size: 4,
+                new_size: 4,
+            },
+            Change::Removed {
+                path: "gone".to_owned(),
+                size: 7,
+            },
+            Change::Modified {
+                path: "grown".to_owned(),
+                old_size: 3,
+                new_size: 6,
+            },
+            Change::Removed {
+                path: "kind".to_owned(),
+                size: 19,
+            },
+            Change::Added {
+                path: "kind/".to_owned(),
+                size: 0,
+            },
+            Change::Added {
+                path: "kind/x".to_owned(),
+                size: 1,
+            },
+            Change::Added {
+                path: "sub/".to_owned(),
+                size: 0,
+            },
+            Change::Renamed {
+                from: "moved".to_owned(),
+                to: "sub/moved".to_owned(),
+                size: 13,
+            },
+        ]
+    }
+
+    #[test]
+    fn two_archives() {
+        let new = archive(
+            "new",
+            &[
+                ("same", b"unchanged"),
+                ("edited", b"abce"),
+                ("grown", b"abcdef"),
+                ("added", b"new"),
+                ("sub/moved", b"moved content"),
+                ("kind/x", b"x"),
+            ],
+        );
+        let diff = archives(&old(), &new).unwrap();
+        assert_eq!(diff.changes, expected());
+        assert_eq!(diff.size_delta(), 3 - 1 + 3 - 7 - 19 + 1);
+        assert!(archives(&new, &new).unwrap().is_empty());
+    }
+
+    #[test]
+    fn archive_and_directory() {
+        let dir = crate::scratch("diff_archive_and_directory");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::create_dir_all(dir.join("kind")).unwrap();
+        for (path, content) in &[
+            ("same", "unchanged"),
+            ("edited", "abce"),
+            ("grown", "abcdef"),
+            ("added", "new"),
+            ("sub/moved", "moved content"),
+            ("kind/x", "x"),
+        ] {
+            std::fs::write(dir.join(path), content).unwrap();
+        }
+        let diff = archive_with_dir(&old(), &dir).unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(diff.changes, expected());
+    }
+
+    #[test]
+    fn last_byte_differs() {
+        // Longer than the chunks compared at once
+        let content = vec![7; 200 * 1024];
+        let mut edited = content.clone();
+        *edited.last_mut().unwrap() = 8;
+        let old = archive("r", &[("a", &content), ("b", &content)]);
+        let new = archive("r", &[("a", &edited), ("c", &edited)]);
+        let size = content.len() as u64;
+        assert_eq!(
+            archives(&old, &new).unwrap().changes,
+            [
+                Change::Modified {
+                    path: "a".to_owned(),
+                    old_size: size,
+                    new_size: size,
+                },
+                Change::Removed {
+                    path: "b".to_owned(),
+                    size,
+                },
+                Change::Added {
+                    path: "c".to_owned(),
+                    size,
+                },
+            ]
+        );
+    }
+}
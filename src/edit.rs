@@ -0,0 +1,823 @@
+//! Update an archive in place, without repacking it
+//!
+//! ```ignore
+//! let mut editor = klu_core::edit::Editor::open("assets.klu")?;
+//! editor.replace("assets/textures/a.png", "build/a.png")?;
+//! editor.rename("assets/b.png", "assets/textures/b.png")?;
+//! editor.remove("assets/old")?;
+//! // Once in a while, to drop the data no entry uses anymore
+//! editor.compact()?;
+//! ```
+//! New data is appended to the archive, and only the headers leading to the changed entry are
+//! rewritten: the directory whose childs change gets a new header at the end of the archive, in
+//! which every registration gives the offset of its child's data (format version 2), and so does
+//! each of its parents up to the first one whose registration already holds an offset, which is
+//! updated where it is.
+//!
+//! Archives written with [write::Archive::write_indexed_to] are never written over: each edit
+//! appends its data, then a new central index and the footer pointing to it.
+//!
+//! In both cases, the data and headers left unused stay in the archive until [Editor::compact]
+use crate::read::{self, PathIndex, RawEntry, ReadError};
+use crate::write::{self, IndexEntry, WriteError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Result type used by in-place edits
+pub type EditResult<T> = Result<T, EditError>;
+
+#[derive(Debug)]
+/// Error editing an archive
+pub enum EditError {
+    /// An IO Error
+    IoError(std::io::Error),
+    /// The archive can't be read
+    ReadError(ReadError),
+    /// A new entry can't be read from the disk
+    WriteError(WriteError),
+    /// No entry at this path
+    NotFound(String),
+    /// An entry already is at this path
+    AlreadyExists(String),
+    /// The path can't be used for this edit, with why
+    InvalidPath(String),
+    /// The archive's format version can't be edited in place
+    UnsupportedVersion(u8),
+    /// The archive has been appended to another file, see [write::Archive::append_to_path]
+    Embedded,
+    /// The archive is split into volumes, see [write::Archive::write_volumes_to_path]
+    Split,
+}
+
+impl From<std::io::Error> for EditError {
+    fn from(err: std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl From<ReadError> for EditError {
+    fn from(err: ReadError) -> Self {
+        Self::ReadError(err)
+    }
+}
+
+impl From<WriteError> for EditError {
+    fn from(err: WriteError) -> Self {
+        Self::WriteError(err)
+    }
+}
+
+impl std::error::Error for EditError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IoError(e) => Some(e),
+            Self::ReadError(e) => Some(e),
+            Self::WriteError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for EditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::IoError(e) => write!(f, "{}", e),
+            Self::ReadError(e) => write!(f, "{}", e),
+            Self::WriteError(e) => write!(f, "{}", e),
+            Self::NotFound(path) => write!(f, "No entry at `{}`", path),
+            Self::AlreadyExists(path) => write!(f, "An entry already is at `{}`", path),
+            Self::InvalidPath(context) => write!(f, "Invalid path: {}", context),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Archives of format version {} can't be edited, repack them",
+                version
+            ),
+            Self::Embedded => write!(f, "Archives appended to another file can't be edited"),
+            Self::Split => write!(f, "Archives split into volumes can't be edited"),
+        }
+    }
+}
+
+/// Offset of the root's registration
+const ROOT_RECORD: u64 = 4 + 8 + 8;
+
+/// Size of the chunks data is copied by
+const CHUNK: usize = 64 * 1024;
+
+#[derive(Debug)]
+/// An archive opened for in-place edits
+///
+/// Paths are given as to [read::Archive], the root's name being optional. Every edit is written
+/// to the archive before it returns
+pub struct Editor {
+    path: PathBuf,
+    file: std::fs::File,
+    archive: read::Archive,
+    /// Kept while the edits of [Editor::atomically] run
+    undo: Option<Undo>,
+}
+
+#[derive(Debug)]
+/// What undoes the edits made since it was started: the archive's length then, and the bytes
+/// written over since, with their offset
+struct Undo {
+    len: u64,
+    overwritten: Vec<(u64, Vec<u8>)>,
+}
+
+impl Editor {
+    /// Open the archive at `path` for edits
+    pub fn open<P: AsRef<Path>>(path: P) -> EditResult<Self> {
+        let path = path.as_ref().to_path_buf();
+        let archive = read::Archive::from_path(&path)?;
+        // Version 0 registrations have no flags to mark those holding an offset
+        if archive.version() == 0 {
+            return Err(EditError::UnsupportedVersion(0));
+        }
+        // Its offsets aren't the file's
+        if archive.is_embedded() {
+            return Err(EditError::Embedded);
+        }
+        if archive.is_split() {
+            return Err(EditError::Split);
+        }
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        Ok(Editor {
+            path,
+            file,
+            archive,
+            undo: None,
+        })
+    }
+
+    /// The archive as it is after the last edit
+    pub fn archive(&self) -> &read::Archive {
+        &self.archive
+    }
+
+    /// Pack the file or directory `source` at `path`, whose parent directory must exist
+    pub fn add<P: AsRef<Path>, S: AsRef<Path>>(&mut self, path: P, source: S) -> EditResult<()> {
+        let (parent, name) = self.parent(path)?;
+        let mut childs = self.archive.raw_dir(&parent[parent.len() - 1])?;
+        if childs.iter().any(|child| child.name == name) {
+            return Err(EditError::AlreadyExists(join(&chain_key(&parent), &name)));
+        }
+        childs.push(self.append_source(&name, source)?);
+        self.rewrite_dir(&parent, childs)?;
+        self.finish()
+    }
+
+    /// Replace the entry at `path` by the file or directory `source`, keeping its name
+    pub fn replace<P: AsRef<Path>, S: AsRef<Path>>(
+        &mut self,
+        path: P,
+        source: S,
+    ) -> EditResult<()> {
+        let chain = self.chain(path)?;
+        let name = chain[chain.len() - 1].name.clone();
+        let entry = self.append_source(&name, source)?;
+        self.relink(&chain, entry)?;
+        self.finish()
+    }
+
+    /// Remove the entry at `path`, with its childs if it is a directory
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> EditResult<()> {
+        let (parent, name) = self.parent(path)?;
+        let mut childs = self.archive.raw_dir(&parent[parent.len() - 1])?;
+        if !childs.iter().any(|child| child.name == name) {
+            return Err(EditError::NotFound(join(&chain_key(&parent), &name)));
+        }
+        childs.retain(|child| child.name != name);
+        self.rewrite_dir(&parent, childs)?;
+        self.finish()
+    }
+
+    /// Move the entry at `from` to `to`, whose parent directory must exist. Its data isn't copied
+    ///
+    /// Moving it to another directory is done in two edits, adding it then removing it. If the
+    /// second one fails, the first one is undone
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) -> EditResult<()> {
+        let chain = self.chain(&from)?;
+        let (to_parent, name) = self.parent(to)?;
+        if chain.len() == 1 {
+            return Err(root_error(&chain_key(&chain)));
+        }
+        if to_parent.len() >= chain.len() && same_names(&to_parent[..chain.len()], &chain) {
+            return Err(EditError::InvalidPath(format!(
+                "`{}` can't be moved inside itself",
+                chain_key(&chain)
+            )));
+        }
+        let mut childs = self.archive.raw_dir(&to_parent[to_parent.len() - 1])?;
+        if childs.iter().any(|child| child.name == name) {
+            return Err(EditError::AlreadyExists(join(
+                &chain_key(&to_parent),
+                &name,
+            )));
+        }
+        let old = &chain[chain.len() - 1];
+        let entry = RawEntry {
+            name,
+            ..old.clone()
+        };
+        if same_names(&chain[..chain.len() - 1], &to_parent) {
+            for child in &mut childs {
+                if child.name == old.name {
+                    *child = entry.clone();
+                }
+            }
+            self.rewrite_dir(&to_parent, childs)?;
+            return self.finish();
+        }
+        childs.push(entry);
+        self.atomically(|editor| {
+            editor.rewrite_dir(&to_parent, childs)?;
+            editor.finish()?;
+            editor.remove(from)
+        })
+    }
+
+    /// Bytes of the archive no entry uses anymore, which [Editor::compact] would reclaim
+    pub fn dead_space(&self) -> EditResult<u64> {
+        let mut root = self.tree()?;
+        let used = if self.indexed() {
+            let data = data_len(&root);
+            let index = write::central_index(&root, 0).len() as u64;
+            4 + data + index + read::FOOTER_LEN
+        } else {
+            inline_sizes(&mut root);
+            ROOT_RECORD + inline_registration(&root).len() as u64 + root.size
+        };
+        Ok(self.archive.raw_len()?.saturating_sub(used))
+    }
+
+    /// Rewrite the archive without the data and headers no entry uses anymore
+    ///
+    /// The compacted archive is written next to the archive, then moved over it. An append-only
+    /// archive keeps its layout, any other takes the one [write::Archive::write_to] produces, so
+    /// it can be read by older versions of this crate
+    pub fn compact(&mut self) -> EditResult<()> {
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".compact");
+        let temp = PathBuf::from(temp);
+        let result = self.write_compacted(&temp);
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp);
+            return result;
+        }
+        std::fs::rename(&temp, &self.path)?;
+        self.file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)?;
+        self.archive = read::Archive::from_path(&self.path)?;
+        Ok(())
+    }
+
+    /// Run `edits`, undoing them all if one fails
+    fn atomically<F: FnOnce(&mut Self) -> EditResult<()>>(&mut self, edits: F) -> EditResult<()> {
+        self.undo = Some(Undo {
+            len: self.file.seek(SeekFrom::End(0))?,
+            overwritten: Vec::new(),
+        });
+        let result = edits(self);
+        let undo = self.undo.take().expect("Only taken here");
+        if result.is_ok() {
+            return result;
+        }
+        for (offset, bytes) in undo.overwritten.iter().rev() {
+            self.file.seek(SeekFrom::Start(*offset))?;
+            self.file.write_all(bytes)?;
+        }
+        // Drops whatever has been appended
+        self.file.set_len(undo.len)?;
+        self.archive = read::Archive::from_path(&self.path)?;
+        result
+    }
+
+    /// Write `bytes` at `offset`, over the archive. What was there is kept if it may be undone
+    fn overwrite(&mut self, offset: u64, bytes: &[u8]) -> EditResult<()> {
+        if let Some(undo) = &mut self.undo {
+            // Past its length, the archive is cut back anyway
+            let n = undo.len.saturating_sub(offset).min(bytes.len() as u64) as usize;
+            let mut old = vec![0; n];
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.read_exact(&mut old)?;
+            undo.overwritten.push((offset, old));
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(bytes)?;
+        Ok(())
+    }
+
+    /// True for append-only archives, see [write::Archive::write_indexed_to]
+    fn indexed(&self) -> bool {
+        self.archive.version() >= 3
+    }
+
+    /// The registrations from the root down to the entry at `path`
+    fn chain<P: AsRef<Path>>(&self, path: P) -> EditResult<Vec<RawEntry>> {
+        let key = key(path)?;
+        match self.archive.raw_path(&key)? {
+            Some(chain) => Ok(chain),
+            None => Err(EditError::NotFound(key)),
+        }
+    }
+
+    /// The chain (see [Editor::chain]) of the parent directory of `path` and the name of the
+    /// entry
+    fn parent<P: AsRef<Path>>(&self, path: P) -> EditResult<(Vec<RawEntry>, String)> {
+        let key = key(path)?;
+        if let Some(chain) = self.archive.raw_path(&key)? {
+            if chain.len() == 1 {
+                return Err(root_error(&key));
+            }
+        }
+        // A single name is a top-level entry, or the root's child if it has a name
+        let (parent_key, name) = key.rsplit_once('/').unwrap_or(("", &key));
+        if name.is_empty() || name.len() > 127 {
+            return Err(EditError::InvalidPath(format!(
+                "`{}` must be 1 to 127 bytes long",
+                name
+            )));
+        }
+        let parent = match self.archive.raw_path(parent_key)? {
+            Some(parent) => parent,
+            None => return Err(EditError::NotFound(parent_key.to_owned())),
+        };
+        if parent[parent.len() - 1].is_file {
+            return Err(EditError::InvalidPath(format!(
+                "`{}` isn't a directory",
+                chain_key(&parent)
+            )));
+        }
+        Ok((parent, name.to_owned()))
+    }
+
+    /// Where to append data
+    fn end(&mut self) -> EditResult<u64> {
+        let len = self.file.seek(SeekFrom::End(0))?;
+        // The root's registration grows when it is given an offset, over what follows it
+        let offset = if self.indexed() {
+            len
+        } else {
+            len.max(ROOT_RECORD + self.archive.headersize() + 8)
+        };
+        self.file.seek(SeekFrom::Start(offset))?;
+        Ok(offset)
+    }
+
+    /// Write `bytes` at the end of the archive, returning their offset
+    fn append(&mut self, bytes: &[u8]) -> EditResult<u64> {
+        let offset = self.end()?;
+        self.file.write_all(bytes)?;
+        Ok(offset)
+    }
+
+    /// Write the data of `source` at the end of the archive, returning the entry named `name`
+    /// holding it, which isn't registered yet
+    fn append_source<S: AsRef<Path>>(&mut self, name: &str, source: S) -> EditResult<RawEntry> {
+        let file = write::File::from_path(source)?;
+        let offset = self.end()?;
+        let mut buffer = std::io::BufWriter::new(&mut self.file);
+        file.write_to_buf(&mut buffer)?;
+        buffer.flush()?;
+        Ok(RawEntry {
+            name: name.to_owned(),
+            is_file: file.is_file(),
+            is_sparse: file.is_sparse(),
+            size: file.filesize(),
+            offset,
+            record_offset: 0,
+            has_offset: false,
+        })
+    }
+
+    /// Give the directory at the end of `chain` the entries `childs`
+    fn rewrite_dir(&mut self, chain: &[RawEntry], childs: Vec<RawEntry>) -> EditResult<()> {
+        if self.indexed() {
+            let key = chain_key(chain);
+            return self.write_index(self.archive.raw_root(), Some((&key, childs)));
+        }
+        let records = childs.iter().map(relocated).collect::<Vec<_>>();
+        let h_size = records.iter().map(|r| r.len() as u64).sum::<u64>();
+        let mut data = h_size.to_be_bytes().to_vec();
+        for record in &records {
+            data.extend_from_slice(record);
+        }
+        let offset = self.append(&data)?;
+        let dir = RawEntry {
+            size: data.len() as u64,
+            offset,
+            ..chain[chain.len() - 1].clone()
+        };
+        self.relink(chain, dir)
+    }
+
+    /// Make the entry at the end of `chain` be `entry` instead, which has the same name
+    fn relink(&mut self, chain: &[RawEntry], entry: RawEntry) -> EditResult<()> {
+        let old = &chain[chain.len() - 1];
+        if !self.indexed() && old.has_offset {
+            // Same name, same fields: it fits where the old one is
+            return self.link(old.record_offset, &relocated(&entry));
+        }
+        if chain.len() == 1 {
+            if self.indexed() {
+                return self.write_index(entry, None);
+            }
+            let record = relocated(&entry);
+            self.link(ROOT_RECORD, &record)?;
+            return self.overwrite(4, &(record.len() as u64).to_be_bytes());
+        }
+        // The entry's data is among its siblings', so they all move out of the parent's
+        let parent = &chain[..chain.len() - 1];
+        let mut childs = self.archive.raw_dir(&parent[parent.len() - 1])?;
+        for child in &mut childs {
+            if child.name == old.name {
+                *child = entry.clone();
+            }
+        }
+        self.rewrite_dir(parent, childs)
+    }
+
+    /// Write `record` at `offset`, the archive's version being raised for it to be read
+    fn link(&mut self, offset: u64, record: &[u8]) -> EditResult<()> {
+        self.overwrite(3, &[2])?;
+        self.overwrite(offset, record)
+    }
+
+    /// Append the central index of the tree below `root`, the directory at the key of `changed`
+    /// holding its entries, then the footer pointing to it
+    fn write_index(
+        &mut self,
+        root: RawEntry,
+        changed: Option<(&str, Vec<RawEntry>)>,
+    ) -> EditResult<()> {
+        let key = chain_key(std::slice::from_ref(&root));
+        let root = self.index_entry(&key, root, &changed, &mut Vec::new())?;
+        let offset = self.end()?;
+        self.file.write_all(&write::central_index(&root, offset))?;
+        self.file
+            .write_all(&write::footer(offset, root.registration_len()))?;
+        Ok(())
+    }
+
+    /// Update the archive's size and read it again
+    fn finish(&mut self) -> EditResult<()> {
+        if !self.indexed() {
+            let len = self.file.seek(SeekFrom::End(0))?;
+            self.overwrite(4 + 8, &len.to_be_bytes())?;
+        }
+        self.archive = read::Archive::from_path(&self.path)?;
+        Ok(())
+    }
+
+    /// Every entry of the archive
+    fn tree(&self) -> EditResult<IndexEntry> {
+        let root = self.archive.raw_root();
+        let key = chain_key(std::slice::from_ref(&root));
+        self.index_entry(&key, root, &None, &mut Vec::new())
+    }
+
+    /// The tree below `entry`, at `key`, the directory at the key of `changed` holding its
+    /// entries. Directories are read from their data, which may have been appended since the
+    /// archive was opened. `ancestors` holds the data offsets of the directories `entry` is in
+    fn index_entry(
+        &self,
+        key: &str,
+        entry: RawEntry,
+        changed: &Option<(&str, Vec<RawEntry>)>,
+        ancestors: &mut Vec<u64>,
+    ) -> EditResult<IndexEntry> {
+        let mut childs = Vec::new();
+        if !entry.is_file {
+            read::enter_dir(ancestors, entry.offset, &entry.name)?;
+            let raw = match changed {
+                Some((changed_key, entries)) if *changed_key == key => entries.clone(),
+                _ => self.archive.raw_dir(&entry)?,
+            };
+            for child in raw {
+                let child_key = join(key, &child.name);
+                childs.push(self.index_entry(&child_key, child, changed, ancestors)?);
+            }
+            ancestors.pop();
+        }
+        Ok(IndexEntry {
+            name: entry.name,
+            is_file: entry.is_file,
+            is_sparse: entry.is_sparse,
+            size: entry.size,
+            offset: entry.offset,
+            childs,
+        })
+    }
+
+    fn write_compacted(&self, path: &Path) -> EditResult<()> {
+        let mut root = self.tree()?;
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let mut chunk = vec![0; CHUNK];
+        if self.indexed() {
+            out.write_all(&write::Archive::INDEXED_ID)?;
+            let mut offset = write::Archive::INDEXED_ID.len() as u64;
+            self.copy_data(&mut root, &mut out, &mut offset, &mut chunk)?;
+            out.write_all(&write::central_index(&root, offset))?;
+            out.write_all(&write::footer(offset, root.registration_len()))?;
+        } else {
+            inline_sizes(&mut root);
+            let record = inline_registration(&root);
+            out.write_all(&write::Archive::ID)?;
+            out.write_all(&(record.len() as u64).to_be_bytes())?;
+            out.write_all(&(ROOT_RECORD + record.len() as u64 + root.size).to_be_bytes())?;
+            out.write_all(&record)?;
+            self.write_inline(&root, &mut out, &mut chunk)?;
+        }
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        Ok(())
+    }
+
+    /// Write the data of every file below `entry`, one after the other, `offset` being where the
+    /// next one goes. Their offsets are updated to where they are written
+    fn copy_data<W: Write>(
+        &self,
+        entry: &mut IndexEntry,
+        out: &mut W,
+        offset: &mut u64,
+        chunk: &mut [u8],
+    ) -> EditResult<()> {
+        if entry.is_file {
+            self.copy(entry, out, chunk)?;
+            entry.offset = *offset;
+            *offset += entry.size;
+        }
+        for child in &mut entry.childs {
+            self.copy_data(child, out, offset, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Write the data of `entry`, sized by [inline_sizes], its childs' data following its header
+    fn write_inline<W: Write>(
+        &self,
+        entry: &IndexEntry,
+        out: &mut W,
+        chunk: &mut [u8],
+    ) -> EditResult<()> {
+        if entry.is_file {
+            return self.copy(entry, out, chunk);
+        }
+        let records = entry
+            .childs
+            .iter()
+            .map(inline_registration)
+            .collect::<Vec<_>>();
+        let h_size = records.iter().map(|r| r.len() as u64).sum::<u64>();
+        out.write_all(&h_size.to_be_bytes())?;
+        for record in &records {
+            out.write_all(record)?;
+        }
+        for child in &entry.childs {
+            self.write_inline(child, out, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Copy the data of the file `entry` to `out`
+    fn copy<W: Write>(&self, entry: &IndexEntry, out: &mut W, chunk: &mut [u8]) -> EditResult<()> {
+        let mut offset = entry.offset;
+        let end = offset + entry.size;
+        while offset < end {
+            let n = (end - offset).min(chunk.len() as u64) as usize;
+            self.archive.read_raw(&mut chunk[..n], offset)?;
+            out.write_all(&chunk[..n])?;
+            offset += n as u64;
+        }
+        Ok(())
+    }
+}
+
+/// Set the size of every directory below `entry` to the one of its data once its childs' data
+/// follows its header
+fn inline_sizes(entry: &mut IndexEntry) {
+    if entry.is_file {
+        return;
+    }
+    entry.size = 8;
+    for child in &mut entry.childs {
+        inline_sizes(child);
+        entry.size += inline_registration(child).len() as u64 + child.size;
+    }
+}
+
+/// The registration of `entry` once its data follows its siblings'
+fn inline_registration(entry: &IndexEntry) -> Box<[u8]> {
+    write::registration(
+        &entry.name,
+        entry.is_file,
+        entry.is_sparse,
+        entry.size,
+        None,
+    )
+}
+
+/// Size of the data of every file below `entry`
+fn data_len(entry: &IndexEntry) -> u64 {
+    if entry.is_file {
+        return entry.size;
+    }
+    entry.childs.iter().map(data_len).sum()
+}
+
+/// The registration of `entry`, giving the offset of its data
+fn relocated(entry: &RawEntry) -> Box<[u8]> {
+    write::registration(
+        &entry.name,
+        entry.is_file,
+        entry.is_sparse,
+        entry.size,
+        Some(entry.offset),
+    )
+}
+
+fn key<P: AsRef<Path>>(path: P) -> EditResult<String> {
+    let path = path.as_ref();
+    PathIndex::key(path)
+        .filter(|key| !key.is_empty())
+        .ok_or_else(|| EditError::InvalidPath(format!("`{}` isn't a path", path.display())))
+}
+
+/// The path of the entry at the end of `chain`
+fn chain_key(chain: &[RawEntry]) -> String {
+    chain
+        .iter()
+        .fold(String::new(), |key, entry| join(&key, &entry.name))
+}
+
+/// `name` in the directory at `key`, which is empty for the nameless root of an archive with
+/// several top-level entries
+fn join(key: &str, name: &str) -> String {
+    if key.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", key, name)
+    }
+}
+
+/// True if both chains go through the same entries
+fn same_names(a: &[RawEntry], b: &[RawEntry]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.name == b.name)
+}
+
+fn root_error(key: &str) -> EditError {
+    EditError::InvalidPath(format!("`{}` is the root", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write::File;
+
+    /// Write the archive holding `sub/b`, `other/` and `a` below a root named `root`, which may
+    /// be empty, at `path`
+    fn write(path: &Path, root: &str, indexed: bool) {
+        let root = File::directory(
+            root,
+            vec![
+                File::from_memory("a", b"first".to_vec()),
+                File::directory("sub", vec![File::from_memory("b", b"second".to_vec())]),
+                File::directory("other", Vec::new()),
+            ],
+        );
+        let archive = write::Archive::from_file(root);
+        if indexed {
+            archive.write_indexed_to_path(path).unwrap();
+        } else {
+            archive.write_to_path(path).unwrap();
+        }
+    }
+
+    fn content(archive: &read::Archive, path: &str) -> Vec<u8> {
+        let mut content = Vec::new();
+        assert!(archive.extract_to(path, &mut content).unwrap(), "{}", path);
+        content
+    }
+
+    /// Every path below the root, and the content of the files
+    fn listing(archive: &read::Archive) -> Vec<(String, Vec<u8>)> {
+        let root = archive.root_name();
+        archive
+            .entries()
+            .unwrap()
+            .into_iter()
+            .filter(|entry| entry.path != format!("{}/", root))
+            .map(|entry| {
+                let content = if entry.is_file {
+                    content(archive, &entry.path)
+                } else {
+                    Vec::new()
+                };
+                let path = match entry.path.strip_prefix(&format!("{}/", root)) {
+                    Some(path) if !root.is_empty() => path.to_owned(),
+                    _ => entry.path,
+                };
+                (path, content)
+            })
+            .collect()
+    }
+
+    fn assert_consistent(path: &Path) {
+        let mut archive = std::fs::File::open(path).unwrap();
+        let mut dump = Vec::new();
+        let found = read::inspect(&mut archive, &mut dump).unwrap();
+        assert!(found.is_empty(), "{}", String::from_utf8_lossy(&dump));
+    }
+
+    /// Every edit on the archive written by [write], whose root is `root`
+    fn edits(name: &str, root: &str, indexed: bool) {
+        let dir = crate::scratch(&format!("edit_{}", name));
+        let path = dir.join("archive.klu");
+        write(&path, root, indexed);
+        std::fs::write(dir.join("new"), b"added").unwrap();
+        std::fs::write(dir.join("replacement"), b"replaced").unwrap();
+        std::fs::create_dir(dir.join("tree")).unwrap();
+        std::fs::write(dir.join("tree/c"), b"third").unwrap();
+
+        let mut editor = Editor::open(&path).unwrap();
+        editor.add("sub/new", dir.join("new")).unwrap();
+        editor.add("other/tree", dir.join("tree")).unwrap();
+        editor.replace("a", dir.join("replacement")).unwrap();
+        editor.rename("sub/b", "sub/renamed").unwrap();
+        editor.rename("sub/renamed", "other/moved").unwrap();
+        editor.remove("sub/new").unwrap();
+        assert!(matches!(
+            editor.add("other/moved", dir.join("new")),
+            Err(EditError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            editor.rename("sub", "sub/inside"),
+            Err(EditError::InvalidPath(_))
+        ));
+        let file = |path: &str, content: &[u8]| (path.to_owned(), content.to_vec());
+        let expected = vec![
+            file("a", b"replaced"),
+            file("sub/", b""),
+            file("other/", b""),
+            file("other/tree/", b""),
+            file("other/tree/c", b"third"),
+            file("other/moved", b"second"),
+        ];
+        assert_eq!(listing(editor.archive()), expected);
+        assert_eq!(listing(&read::Archive::from_path(&path).unwrap()), expected);
+        assert_consistent(&path);
+
+        assert!(editor.dead_space().unwrap() > 0);
+        editor.compact().unwrap();
+        assert_eq!(editor.dead_space().unwrap(), 0);
+        assert_eq!(listing(editor.archive()), expected);
+        assert_eq!(editor.archive().version() >= 3, indexed);
+        assert_consistent(&path);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn inline_archive() {
+        edits("inline", "root", false);
+    }
+
+    #[test]
+    fn indexed_archive() {
+        edits("indexed", "root", true);
+    }
+
+    #[test]
+    fn several_roots() {
+        edits("several_roots", "", false);
+        edits("several_roots_indexed", "", true);
+    }
+
+    #[test]
+    fn undone_edits() {
+        for indexed in &[false, true] {
+            let dir = crate::scratch(&format!("edit_undone_{}", indexed));
+            let path = dir.join("archive.klu");
+            write(&path, "root", *indexed);
+            std::fs::write(dir.join("new"), b"added").unwrap();
+            // An edit that went through, as the first step of a move into another directory
+            let mut editor = Editor::open(&path).unwrap();
+            editor.replace("a", dir.join("new")).unwrap();
+            let before = std::fs::read(&path).unwrap();
+            let result = editor.atomically(|editor| {
+                editor.add("sub/new", dir.join("new"))?;
+                editor.replace("a", dir.join("new"))?;
+                Err(EditError::NotFound("second step".to_owned()))
+            });
+            assert!(matches!(result, Err(EditError::NotFound(_))));
+            assert_eq!(std::fs::read(&path).unwrap(), before);
+            assert!(!editor.archive().path_exist("sub/new"));
+            assert_consistent(&path);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
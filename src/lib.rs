@@ -13,3 +13,17 @@
 */
 pub mod read;
 pub mod write;
+pub mod build;
+pub mod delta;
+pub mod diff;
+pub mod edit;
+mod hash;
+
+#[cfg(test)]
+/// A fresh directory for the unit test `name`, like `scratch` in `tests/common`
+pub(crate) fn scratch(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("klu_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,158 @@
+use std::path::Path;
+
+#[derive(Debug, Clone, Default)]
+/// Sorted index of every path of an [super::Archive], looked up by binary search
+///
+/// Paths are stored without a trailing `/`, the root's name being their first component unless
+/// the archive has several top-level entries
+pub struct PathIndex {
+    /// (path, index of the entry among its parent's childs for each level below the root)
+    entries: Vec<(String, Box<[u32]>)>,
+}
+
+impl PathIndex {
+    pub(crate) fn new(mut entries: Vec<(String, Box<[u32]>)>) -> Self {
+        entries.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        PathIndex { entries }
+    }
+
+    /// Turn a path into its key in the index, [None] if it isn't valid UTF-8
+    pub(crate) fn key<P: AsRef<Path>>(path: P) -> Option<String> {
+        let mut key = String::new();
+        for component in path.as_ref().iter() {
+            if !key.is_empty() {
+                key.push('/');
+            }
+            key.push_str(component.to_str()?);
+        }
+        Some(key)
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<&[u32]> {
+        self.entries
+            .binary_search_by(|(path, _)| path.as_str().cmp(key))
+            .ok()
+            .map(|i| &*self.entries[i].1)
+    }
+
+    /// Returns true if there is an entry at `path`
+    pub fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
+        Self::key(path).is_some_and(|key| self.get(&key).is_some())
+    }
+
+    /// Number of entries in the archive, the root included if there is one
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the index holds no entry
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Every path of the archive, sorted
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(entry_path)
+    }
+
+    /// The entry at `prefix` then every path below it, sorted, found without going through the
+    /// whole index. `with_prefix("archive/textures")` gives that directory and its content, but
+    /// not `archive/textures2`
+    pub fn with_prefix(&self, prefix: &str) -> impl Iterator<Item = &str> {
+        let prefix = prefix.trim_end_matches('/');
+        if prefix.is_empty() {
+            return [].iter().chain(&self.entries[..]).map(entry_path);
+        }
+        let entry: &[_] = match self
+            .entries
+            .binary_search_by(|(path, _)| path.as_str().cmp(prefix))
+        {
+            Ok(i) => &self.entries[i..=i],
+            Err(_) => &[],
+        };
+        // Paths below it are next to each other, but can be apart from the entry itself: `a/b-c`
+        // sorts between `a/b` and `a/b/c`
+        let below = format!("{}/", prefix);
+        let start = self
+            .entries
+            .partition_point(|(path, _)| path.as_str() < below.as_str());
+        let end = start
+            + self.entries[start..].partition_point(|(path, _)| path.starts_with(below.as_str()));
+        entry
+            .iter()
+            .chain(&self.entries[start..end])
+            .map(entry_path)
+    }
+}
+
+fn entry_path((path, _): &(String, Box<[u32]>)) -> &str {
+    path.as_str()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathIndex;
+
+    fn index(paths: &[&str]) -> PathIndex {
+        PathIndex::new(
+            paths
+                .iter()
+                .map(|path| (path.to_string(), Box::from(&[][..])))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn with_prefix() {
+        let index = index(&[
+            "root/dir2",
+            "root/dir/b",
+            "root",
+            "root/dir-a",
+            "root/dir",
+            "root/dir/a/c",
+            "root/dir/a",
+            "root/dir.txt",
+        ]);
+        let subtree = ["root/dir", "root/dir/a", "root/dir/a/c", "root/dir/b"];
+        assert_eq!(index.with_prefix("root/dir").collect::<Vec<_>>(), subtree);
+        assert_eq!(index.with_prefix("root/dir/").collect::<Vec<_>>(), subtree);
+        assert_eq!(
+            index.with_prefix("root/dir/a").collect::<Vec<_>>(),
+            ["root/dir/a", "root/dir/a/c"]
+        );
+        assert_eq!(
+            index.with_prefix("root/dir2").collect::<Vec<_>>(),
+            ["root/dir2"]
+        );
+        // Not a whole component
+        assert_eq!(index.with_prefix("root/di").count(), 0);
+        assert_eq!(index.with_prefix("missing").count(), 0);
+        assert_eq!(index.with_prefix("").count(), index.len());
+    }
+
+    #[test]
+    fn contains() {
+        let index = index(&["root", "root/dir", "root/dir/file"]);
+        // Files and directories, with or without a trailing `/`
+        assert!(index.contains("root/dir/file"));
+        assert!(index.contains("root/dir"));
+        assert!(index.contains("root/dir/"));
+        assert!(index.contains("root"));
+        assert!(!index.contains("root/dir/missing"));
+        assert!(!index.contains("root/di"));
+        assert!(!index.contains("dir/file"));
+    }
+
+    #[test]
+    fn iter() {
+        let index = index(&["root/b", "root", "root/a/c", "root/a-b", "root/a"]);
+        assert_eq!(
+            index.iter().collect::<Vec<_>>(),
+            ["root", "root/a", "root/a-b", "root/a/c", "root/b"]
+        );
+        assert_eq!(index.len(), 5);
+        assert!(!index.is_empty());
+        assert!(PathIndex::default().is_empty());
+    }
+}
@@ -0,0 +1,595 @@
+use super::utils;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Something wrong found by [inspect]
+pub struct Inconsistency {
+    /// Absolute offset of the faulty field
+    pub offset: u64,
+    /// What is wrong with it
+    pub message: String,
+}
+
+/// Write an annotated dump of the archive read from `archive` to `out`: every header field with
+/// its absolute offset, every entry's registration with its decoded flags and size, and each
+/// directory's header size. Inconsistencies are written where they are found, and returned
+///
+/// Unlike [super::Archive], this doesn't trust any size before checking it, so it can walk a
+/// corrupt archive and show where it goes wrong
+pub fn inspect<R: Read + Seek, W: Write>(
+    archive: &mut R,
+    out: &mut W,
+) -> std::io::Result<Vec<Inconsistency>> {
+    let len = archive.seek(SeekFrom::End(0))?;
+    let mut inspector = Inspector {
+        archive,
+        out,
+        len,
+        version: 0,
+        found: Vec::new(),
+        ancestors: Vec::new(),
+    };
+    inspector.archive_header()?;
+    Ok(inspector.found)
+}
+
+/// A file registration, decoded without trusting it
+struct Record {
+    name: String,
+    is_file: bool,
+    flags: u8,
+    size: u64,
+    /// Where the data is, if the registration gives it rather than following its siblings'
+    data_offset: Option<u64>,
+}
+
+struct Inspector<'a, R, W> {
+    archive: &'a mut R,
+    out: &'a mut W,
+    /// Length of the archive
+    len: u64,
+    version: u8,
+    found: Vec<Inconsistency>,
+    /// Data offsets of the directories being walked, see [super::enter_dir]
+    ancestors: Vec<u64>,
+}
+
+impl<R: Read + Seek, W: Write> Inspector<'_, R, W> {
+    /// The `n` bytes at `offset`, [None] if the archive is too short
+    fn read(&mut self, offset: u64, n: u64) -> std::io::Result<Option<Vec<u8>>> {
+        if offset.checked_add(n).is_none_or(|end| end > self.len) {
+            return Ok(None);
+        }
+        let mut buffer = vec![0; n as usize];
+        self.archive.seek(SeekFrom::Start(offset))?;
+        self.archive.read_exact(&mut buffer)?;
+        Ok(Some(buffer))
+    }
+
+    fn read_u64(&mut self, offset: u64) -> std::io::Result<Option<u64>> {
+        Ok(self.read(offset, 8)?.map(|b| utils::slice_to_u64(&b)))
+    }
+
+    fn line(&mut self, offset: u64, depth: usize, text: &str) -> std::io::Result<()> {
+        writeln!(
+            self.out,
+            "{:#012x}  {:indent$}{}",
+            offset,
+            "",
+            text,
+            indent = depth * 2
+        )
+    }
+
+    fn problem(&mut self, offset: u64, depth: usize, message: String) -> std::io::Result<()> {
+        self.line(offset, depth, &format!("!! {}", message))?;
+        self.found.push(Inconsistency { offset, message });
+        Ok(())
+    }
+
+    /// Report `what`, at `offset`, going past the end of the archive
+    fn truncated(&mut self, offset: u64, depth: usize, what: &str) -> std::io::Result<()> {
+        let message = format!("{} is cut, the archive ends at {:#x}", what, self.len);
+        self.problem(offset, depth, message)
+    }
+
+    fn archive_header(&mut self) -> std::io::Result<()> {
+        let id = match self.read(0, 4)? {
+            Some(id) => id,
+            None => {
+                return self.truncated(0, 0, "ID");
+            }
+        };
+        let escaped = id
+            .iter()
+            .flat_map(|b| std::ascii::escape_default(*b))
+            .map(char::from)
+            .collect::<String>();
+        self.version = id[3];
+        self.line(0, 0, &format!("ID \"{}\", version {}", escaped, id[3]))?;
+        if id[0..3] != super::Archive::ID[0..3] {
+            return self.problem(0, 0, "not a KLU archive".to_owned());
+        }
+        if id[3] > super::Archive::ID[3] {
+            return self.problem(0, 0, format!("unknown format version {}", id[3]));
+        }
+        if self.version >= 3 {
+            return self.footer();
+        }
+        let (headersize, filesize) = match (self.read_u64(4)?, self.read_u64(12)?) {
+            (Some(h), Some(f)) => (h, f),
+            _ => {
+                return self.truncated(4, 0, "archive header");
+            }
+        };
+        self.line(4, 0, &format!("root registration size {}", headersize))?;
+        self.line(12, 0, &format!("archive size {}", filesize))?;
+        if filesize != self.len {
+            let message = format!(
+                "archive size is {} but the archive is {} bytes",
+                filesize, self.len
+            );
+            self.problem(12, 0, message)?;
+        }
+        self.root(20, headersize, 4)
+    }
+
+    /// The footer of an append-only archive, then the tree from the root it points to
+    fn footer(&mut self) -> std::io::Result<()> {
+        let offset = match self.len.checked_sub(utils::FOOTER_LEN) {
+            Some(offset) if offset >= 4 => offset,
+            _ => return self.truncated(4, 0, "footer"),
+        };
+        let footer = match self.read(offset, utils::FOOTER_LEN)? {
+            Some(footer) => footer,
+            None => return self.truncated(offset, 0, "footer"),
+        };
+        let (root, headersize) = (
+            utils::slice_to_u64(&footer[0..8]),
+            utils::slice_to_u64(&footer[8..16]),
+        );
+        self.line(offset, 0, &format!("root registration at {:#x}", root))?;
+        self.line(
+            offset + 8,
+            0,
+            &format!("root registration size {}", headersize),
+        )?;
+        if footer[16..] != utils::FOOTER_MAGIC {
+            return self.problem(offset + 16, 0, "footer magic is missing".to_owned());
+        }
+        self.line(offset + 16, 0, "footer magic")?;
+        self.root(root, headersize, offset + 8)
+    }
+
+    /// The root's registration of `headersize` bytes at `offset`, then its data. `size_field` is
+    /// where `headersize` is read from
+    fn root(&mut self, offset: u64, headersize: u64, size_field: u64) -> std::io::Result<()> {
+        let root = match self.record(offset, headersize, 0)? {
+            // Without the right size, where the root's data starts is unknown
+            Some((_, len)) if len != headersize => {
+                let message = format!("root registration is {} bytes, not {}", len, headersize);
+                return self.problem(size_field, 0, message);
+            }
+            Some((root, _)) => root,
+            None => return Ok(()),
+        };
+        let data = root.data_offset.unwrap_or(offset + headersize);
+        if self.entry(&root, data, 0)? {
+            let end = data + root.size;
+            // Edited archives leave data they no longer use anywhere
+            if self.version < 2 && end < self.len {
+                let message = format!("{} bytes after the root's data", self.len - end);
+                self.problem(end, 0, message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode and print the registration at `offset`, at most `max` bytes long
+    /// Returns it with its length, or [None] if it can't be read
+    fn record(
+        &mut self,
+        offset: u64,
+        max: u64,
+        depth: usize,
+    ) -> std::io::Result<Option<(Record, u64)>> {
+        // The flags byte tells whether the registration holds a data offset
+        let head = if self.version >= 1 { 2 } else { 1 };
+        let first = match self.read(offset, head)? {
+            Some(first) if max >= head => first,
+            _ => {
+                self.truncated(offset, depth, "registration")?;
+                return Ok(None);
+            }
+        };
+        let len = utils::header_len(&first, self.version) as u64;
+        let bytes = match self.read(offset, len)? {
+            Some(bytes) if len <= max => bytes,
+            _ => {
+                let message = format!("registration of {} bytes doesn't fit", len);
+                self.problem(offset, depth, message)?;
+                return Ok(None);
+            }
+        };
+        let start = if self.version >= 1 { 2 } else { 1 };
+        let flags = if self.version >= 1 { bytes[1] } else { 0 };
+        let known = if self.version >= 2 {
+            utils::FLAG_SPARSE | utils::FLAG_OFFSET
+        } else {
+            utils::FLAG_SPARSE
+        };
+        let (data_offset, name_start) = if flags & known & utils::FLAG_OFFSET != 0 {
+            let at = start + 8;
+            (Some(utils::slice_to_u64(&bytes[at..at + 8])), at + 8)
+        } else {
+            (None, start + 8)
+        };
+        let raw_name = &bytes[name_start..];
+        let record = Record {
+            name: String::from_utf8_lossy(raw_name).into_owned(),
+            is_file: bytes[0] & 1 == 1,
+            flags,
+            size: utils::slice_to_u64(&bytes[start..start + 8]),
+            data_offset,
+        };
+        let mut flags = format!("{:#04x}", record.flags);
+        if record.flags & utils::FLAG_SPARSE != 0 {
+            flags.push_str(" (sparse)");
+        }
+        let mut text = format!(
+            "{} {:?}, flags {}, size {}",
+            if record.is_file { "file" } else { "directory" },
+            record.name,
+            flags,
+            record.size
+        );
+        if let Some(data_offset) = record.data_offset {
+            text.push_str(&format!(", data at {:#x}", data_offset));
+        }
+        self.line(offset, depth, &text)?;
+        if std::str::from_utf8(raw_name).is_err() {
+            self.problem(offset, depth, "name isn't valid UTF-8".to_owned())?;
+        }
+        if record.flags & !known != 0 {
+            let message = format!("unknown flags {:#04x}", record.flags & !known);
+            self.problem(offset + 1, depth, message)?;
+        }
+        if !record.is_file && record.flags & utils::FLAG_SPARSE != 0 {
+            self.problem(offset + 1, depth, "sparse flag on a directory".to_owned())?;
+        }
+        Ok(Some((record, len)))
+    }
+
+    /// Print the data of `record`, found at `offset`
+    /// Returns false if it goes past the end of the archive, in which case it isn't read
+    fn entry(&mut self, record: &Record, offset: u64, depth: usize) -> std::io::Result<bool> {
+        if offset
+            .checked_add(record.size)
+            .is_none_or(|end| end > self.len)
+        {
+            self.truncated(offset, depth, &format!("data of {:?}", record.name))?;
+            return Ok(false);
+        }
+        if !record.is_file {
+            if self.ancestors.contains(&offset) {
+                let message = format!("directory {:?} is inside itself", record.name);
+                self.problem(offset, depth + 1, message)?;
+                return Ok(true);
+            }
+            self.ancestors.push(offset);
+            let result = self.directory(record, offset, depth + 1);
+            self.ancestors.pop();
+            result?;
+        } else if record.flags & utils::FLAG_SPARSE != 0 {
+            self.sparse(record, offset, depth + 1)?;
+        } else {
+            let text = format!("data of {:?}, {} bytes", record.name, record.size);
+            self.line(offset, depth + 1, &text)?;
+        }
+        Ok(true)
+    }
+
+    fn directory(&mut self, record: &Record, offset: u64, depth: usize) -> std::io::Result<()> {
+        let h_size = match self.read_u64(offset)? {
+            Some(h_size) if record.size >= 8 => h_size,
+            _ => {
+                let message = format!("directory {:?} can't hold its header size", record.name);
+                return self.problem(offset, depth, message);
+            }
+        };
+        let text = format!("header size {} (directory {:?})", h_size, record.name);
+        self.line(offset, depth, &text)?;
+        if h_size > record.size - 8 {
+            let message = format!(
+                "header doesn't fit in the {} bytes of the directory",
+                record.size
+            );
+            return self.problem(offset, depth, message);
+        }
+        let mut childs = Vec::new();
+        let mut pos = 0;
+        while pos < h_size {
+            match self.record(offset + 8 + pos, h_size - pos, depth)? {
+                Some((child, len)) => {
+                    pos += len;
+                    childs.push(child);
+                }
+                None => break,
+            }
+        }
+        // The childs' data follow the header, one after the other, unless they are elsewhere
+        let mut data = offset + 8 + h_size;
+        let end = offset + record.size;
+        for child in &childs {
+            if let Some(at) = child.data_offset {
+                self.entry(child, at, depth)?;
+                continue;
+            }
+            if data.checked_add(child.size).is_none_or(|e| e > end) {
+                let message = format!(
+                    "data of {:?} goes past the end of directory {:?}",
+                    child.name, record.name
+                );
+                return self.problem(data, depth, message);
+            }
+            self.entry(child, data, depth)?;
+            data += child.size;
+        }
+        if data != end {
+            let message = format!(
+                "childs of {:?} hold {} bytes, not the {} of the directory",
+                record.name,
+                data - offset,
+                record.size
+            );
+            self.problem(data, depth, message)?;
+        }
+        Ok(())
+    }
+
+    fn sparse(&mut self, record: &Record, offset: u64, depth: usize) -> std::io::Result<()> {
+        let (apparent_size, count) = match (self.read_u64(offset)?, self.read_u64(offset + 8)?) {
+            (Some(a), Some(c)) if record.size >= 16 => (a, c),
+            _ => {
+                let message = format!("sparse map of {:?} is cut", record.name);
+                return self.problem(offset, depth, message);
+            }
+        };
+        self.line(offset, depth, &format!("apparent size {}", apparent_size))?;
+        self.line(offset + 8, depth, &format!("extent count {}", count))?;
+        let map_len = count.checked_mul(16).and_then(|n| n.checked_add(16));
+        if map_len.is_none_or(|len| len > record.size) {
+            let message = format!("{} extents don't fit in {} bytes", count, record.size);
+            return self.problem(offset + 8, depth, message);
+        }
+        let mut stored = 0_u64;
+        let mut previous_end = 0;
+        for i in 0..count {
+            let at = offset + 16 + 16 * i;
+            let (start, length) = match (self.read_u64(at)?, self.read_u64(at + 8)?) {
+                (Some(s), Some(l)) => (s, l),
+                _ => {
+                    return self.truncated(at, depth, "extent");
+                }
+            };
+            self.line(at, depth, &format!("extent at {}, {} bytes", start, length))?;
+            match start.checked_add(length) {
+                Some(end) if start >= previous_end && end <= apparent_size => previous_end = end,
+                _ => {
+                    let message = "extent out of order or past the apparent size".to_owned();
+                    self.problem(at, depth, message)?;
+                }
+            }
+            stored = stored.saturating_add(length);
+        }
+        let data = offset + 16 + 16 * count;
+        self.line(data, depth, &format!("extents' data, {} bytes", stored))?;
+        if Some(stored) != record.size.checked_sub(16 + 16 * count) {
+            let message = format!(
+                "extents hold {} bytes, the stored size leaves {}",
+                stored,
+                record.size - (16 + 16 * count)
+            );
+            self.problem(data, depth, message)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(version: u8, name: &str, is_file: bool, flags: u8, size: u64) -> Vec<u8> {
+        let mut record = vec![(name.len() << 1) as u8 | is_file as u8];
+        if version >= 1 {
+            record.push(flags);
+        }
+        record.extend_from_slice(&size.to_be_bytes());
+        record.extend_from_slice(name.as_bytes());
+        record
+    }
+
+    /// A `version` archive whose root directory holds the files `(name, flags, data)`
+    fn archive(version: u8, files: &[(&str, u8, &[u8])]) -> Vec<u8> {
+        let mut header = Vec::new();
+        let mut data = Vec::new();
+        for (name, flags, content) in files {
+            header.extend(record(version, name, true, *flags, content.len() as u64));
+            data.extend_from_slice(content);
+        }
+        let size = 8 + header.len() + data.len();
+        let root = record(version, "root", false, 0, size as u64);
+        let mut archive = vec![b'K', b'L', b'U', version];
+        archive.extend_from_slice(&(root.len() as u64).to_be_bytes());
+        archive.extend_from_slice(&((20 + root.len() + size) as u64).to_be_bytes());
+        archive.extend(root);
+        archive.extend_from_slice(&(header.len() as u64).to_be_bytes());
+        archive.extend(header);
+        archive.extend(data);
+        archive
+    }
+
+    /// A sparse map of `apparent_size` bytes with `extents`, followed by their data
+    fn sparse(apparent_size: u64, extents: &[(u64, u64)]) -> Vec<u8> {
+        let mut map = apparent_size.to_be_bytes().to_vec();
+        map.extend_from_slice(&(extents.len() as u64).to_be_bytes());
+        for (offset, length) in extents {
+            map.extend_from_slice(&offset.to_be_bytes());
+            map.extend_from_slice(&length.to_be_bytes());
+        }
+        map.extend(
+            extents
+                .iter()
+                .flat_map(|(_, length)| vec![1; *length as usize]),
+        );
+        map
+    }
+
+    fn findings(archive: &[u8]) -> Vec<String> {
+        let mut dump = Vec::new();
+        let found = inspect(&mut std::io::Cursor::new(archive), &mut dump).unwrap();
+        // Each finding is also in the dump
+        let dump = String::from_utf8(dump).unwrap();
+        for inconsistency in &found {
+            assert!(dump.contains(&format!("!! {}", inconsistency.message)));
+        }
+        found.into_iter().map(|i| i.message).collect()
+    }
+
+    /// Written by the writer rather than by hand
+    fn written(indexed: bool) -> Vec<u8> {
+        use crate::write::{Archive, File};
+        let root = File::directory(
+            "root",
+            vec![
+                File::from_memory("a", b"first".to_vec()),
+                File::directory("d", vec![File::from_memory("b", b"second".to_vec())]),
+            ],
+        );
+        let mut bytes = Vec::new();
+        if indexed {
+            Archive::from_file(root)
+                .write_indexed_to(&mut bytes)
+                .unwrap();
+        } else {
+            Archive::from_file(root).write_to(&mut bytes).unwrap();
+        }
+        bytes
+    }
+
+    #[test]
+    fn valid_archives() {
+        let files: &[(&str, u8, &[u8])] = &[("a", 0, b"first"), ("b", 0, b"")];
+        assert!(findings(&archive(0, files)).is_empty());
+        assert!(findings(&archive(1, files)).is_empty());
+        let sparse = sparse(100, &[(0, 10), (50, 10)]);
+        let files: &[(&str, u8, &[u8])] = &[("s", utils::FLAG_SPARSE, &sparse), ("a", 0, b"x")];
+        assert!(findings(&archive(1, files)).is_empty());
+        assert!(findings(&written(false)).is_empty());
+        assert!(findings(&written(true)).is_empty());
+    }
+
+    #[test]
+    fn valid_edited_archive() {
+        // The data of `f` has been appended after the root's, its registration pointing to it
+        let mut f = record(2, "f", true, utils::FLAG_OFFSET, 4);
+        let f_len = f.len() + 8;
+        let root = record(2, "root", false, 0, 8 + f_len as u64);
+        let data = 20 + root.len() + 8 + f_len;
+        f.splice(10..10, (data as u64).to_be_bytes().iter().copied());
+        let mut archive = b"KLU\x02".to_vec();
+        archive.extend_from_slice(&(root.len() as u64).to_be_bytes());
+        archive.extend_from_slice(&(data as u64 + 4).to_be_bytes());
+        archive.extend(root);
+        archive.extend_from_slice(&(f_len as u64).to_be_bytes());
+        archive.extend(f);
+        archive.extend_from_slice(b"data");
+        assert!(findings(&archive).is_empty());
+    }
+
+    #[test]
+    fn directory_inside_itself() {
+        // `loop` points back at the data of the root, which holds it
+        let mut child = record(2, "loop", false, utils::FLAG_OFFSET, 0);
+        let child_len = child.len() + 8;
+        let size = 8 + child_len as u64;
+        child[2..10].copy_from_slice(&size.to_be_bytes());
+        let root = record(2, "root", false, 0, size);
+        let data = (20 + root.len()) as u64;
+        child.splice(10..10, data.to_be_bytes().iter().copied());
+        let mut archive = b"KLU\x02".to_vec();
+        archive.extend_from_slice(&(root.len() as u64).to_be_bytes());
+        archive.extend_from_slice(&(data + size).to_be_bytes());
+        archive.extend(root);
+        archive.extend_from_slice(&(child_len as u64).to_be_bytes());
+        archive.extend(child);
+        assert_eq!(findings(&archive), ["directory \"loop\" is inside itself"]);
+
+        let read = super::super::Archive::from_bytes(archive).unwrap();
+        let cycle = |result: super::super::ReadResult<()>| match result {
+            Err(super::super::ReadError::InvalidArchive(message)) => {
+                assert_eq!(message, "directory \"loop\" is inside itself")
+            }
+            other => panic!("{:?}", other),
+        };
+        cycle(read.entries().map(drop));
+        cycle(read.index().map(drop));
+        let out = crate::scratch("inspect_directory_inside_itself");
+        cycle(read.release(&out));
+        std::fs::remove_dir_all(&out).unwrap();
+        assert_eq!(read.paths(), ["root/", "root/loop/"]);
+    }
+
+    #[test]
+    fn truncated_data() {
+        let archive = archive(1, &[("a", 0, b"first")]);
+        let found = findings(&archive[..archive.len() - 2]);
+        assert!(found[0].starts_with("archive size is"), "{:?}", found);
+        assert!(
+            found
+                .iter()
+                .any(|m| m.starts_with("data of \"root\" is cut")),
+            "{:?}",
+            found
+        );
+    }
+
+    #[test]
+    fn header_size_past_the_end() {
+        let mut archive = archive(1, &[("a", 0, b"first")]);
+        // The root's registration
+        archive[4..12].copy_from_slice(&1000_u64.to_be_bytes());
+        assert_eq!(
+            findings(&archive),
+            ["root registration is 14 bytes, not 1000"]
+        );
+        // The root directory's header
+        let mut archive = self::archive(1, &[("a", 0, b"first")]);
+        let at = 20 + record(1, "root", false, 0, 0).len();
+        archive[at..at + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert_eq!(
+            findings(&archive),
+            ["header doesn't fit in the 24 bytes of the directory"]
+        );
+    }
+
+    #[test]
+    fn bad_sparse_maps() {
+        for extents in &[[(0, 10), (5, 10)], [(50, 10), (0, 10)], [(0, 10), (95, 10)]] {
+            let sparse = sparse(100, extents);
+            let archive = archive(1, &[("s", utils::FLAG_SPARSE, &sparse)]);
+            assert_eq!(
+                findings(&archive),
+                ["extent out of order or past the apparent size"],
+                "{:?}",
+                extents
+            );
+        }
+        // A count too large for the stored size
+        let mut sparse = sparse(100, &[(0, 10)]);
+        sparse[8..16].copy_from_slice(&1000_u64.to_be_bytes());
+        let archive = archive(1, &[("s", utils::FLAG_SPARSE, &sparse)]);
+        assert_eq!(findings(&archive), ["1000 extents don't fit in 42 bytes"]);
+    }
+}
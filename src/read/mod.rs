@@ -15,16 +15,36 @@
 
 /*
  * Archive:
- *      0x00 - 0x03: b"KLU "
+ *      0x00 - 0x03: b"KLU" + format version (0x01, 0x02 once edited in place, 0x03 append-only)
  *      0x04 - 0x0B: headersize (u64)
  *      0x0C - 0x13: file size  (u64)
  *      0x13 - 0x13 + headersize: File Registrations;
  *      0x14 + headersize - EOF : File Data
+ *  Append-only archive (version 3), registrations as in version 2:
+ *      0x00 - 0x03: b"KLU\x03"
+ *      then the files' data, then the central index: the root's registration followed by the
+ *      header of every directory, each registration giving the offset of its data
+ *      EOF - 0x14 - EOF - 0x0D: offset of the root's registration (u64)
+ *      EOF - 0x0C - EOF - 0x05: headersize (u64)
+ *      EOF - 0x04 - EOF - 0x01: b"KLUI"
+ *      Appending writes the new data then a new central index and footer, the last footer
+ *      pointing to the latest index
+ *  Archive appended to another file:
+ *      the other file, then the archive, then the trailer:
+ *      EOF - 0x0C - EOF - 0x05: offset of the archive (u64)
+ *      EOF - 0x04 - EOF - 0x01: b"KLUE"
+ *  Archive split into volumes:
+ *      `<name>.001`, `<name>.002`... holding the archive's bytes one after the other
+ *  Archive with several top-level entries:
+ *      the root is a directory with an empty Filename, the top-level entries being its childs
  *  File Registrations:
  *                                  0b_______*
  *      0x0: 7b => Filename length; 1b: dir flag (0=dir;1=file)
- *      0x1 - 0x08 => File Size
- *      0x09 + 0x09 + Filename length => Filename
+ *      0x1 => Flags (0b_______*: sparse; 0b______*_: data offset, version 2)
+ *      0x2 - 0x09 => File Size (size of the data stored in the archive)
+ *      With the data offset flag:
+ *          0x0A - 0x11 => Data offset (absolute, the data isn't with the other childs')
+ *      then Filename
  *  File:
  *      Is a dir:
  *          0x00 - 0x07: Headersize
@@ -32,10 +52,26 @@
  *          0x09 - 0x09 + dir size: files data
  *      Is a file:
  *          0x0 - 0x0 + filesize : raw bytes
+ *      Is a sparse file:
+ *          0x00 - 0x07: Apparent size
+ *          0x08 - 0x0F: Extent count
+ *          0x10 - 0x10 + 16 * count: Extents (offset (u64), length (u64))
+ *          then the extents' bytes, one after the other
  */
+mod index;
+mod inspect;
+mod source;
 mod utils;
+pub use index::PathIndex;
+pub use inspect::{inspect, Inconsistency};
 use std::io::prelude::*;
+use std::io::SeekFrom;
 use std::path::Path;
+use std::sync::{Arc, OnceLock};
+pub(crate) use utils::FOOTER_LEN;
+
+/// The reader used to parse and extract the archive
+type ArchiveReader<'a> = std::io::BufReader<source::Reader<'a>>;
 
 /// Result type use for reading an archive
 pub type ReadResult<T> = Result<T, ReadError>;
@@ -45,8 +81,8 @@ pub type ReadResult<T> = Result<T, ReadError>;
 pub enum ReadError {
     /// An IO Error
     IoError(std::io::Error),
-    /// The archive isn't valid
-    InvalidArchive,
+    /// The archive isn't valid, with what was found wrong
+    InvalidArchive(String),
     /// The Out-Dir doesn't exist
     InexistantOut,
 }
@@ -57,6 +93,15 @@ impl std::convert::From<std::io::Error> for ReadError {
     }
 }
 
+impl std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IoError(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
 impl std::fmt::Display for ReadError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         write!(
@@ -64,145 +109,847 @@ impl std::fmt::Display for ReadError {
             "{}",
             match self {
                 Self::IoError(e) => e.to_string(),
-                Self::InvalidArchive => "File isn't a valid archive".to_string(),
+                Self::InvalidArchive(context) => format!("File isn't a valid archive: {}", context),
                 Self::InexistantOut => "Path given to release archive do not exist".to_string(),
             }
         )
     }
 }
 
+/// `a + b`, sizes read from the archive going over [u64::MAX] making it invalid
+fn checked_add(a: u64, b: u64, what: &str) -> ReadResult<u64> {
+    a.checked_add(b).ok_or_else(|| {
+        ReadError::InvalidArchive(format!("size of {} overflows ({} + {})", what, a, b))
+    })
+}
+
+/// Fails if `name` isn't a single path component, as extracting `../x` would escape the output
+/// directory
+fn check_name(name: &str) -> ReadResult<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(&['/', '\\', '\0'][..]) {
+        return Err(ReadError::InvalidArchive(format!(
+            "entry name {:?} isn't a single path component",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Push the data offset of the directory `name` onto `ancestors`, those of the directories it
+/// is in. Fails if it is already there: only a data offset stored in a registration can point
+/// back at a directory's own data, and the tree below would never end
+pub(crate) fn enter_dir(ancestors: &mut Vec<u64>, offset: u64, name: &str) -> ReadResult<()> {
+    if ancestors.contains(&offset) {
+        return Err(ReadError::InvalidArchive(format!(
+            "directory {:?} is inside itself",
+            name
+        )));
+    }
+    ancestors.push(offset);
+    Ok(())
+}
+
+/// A size read from the archive as a [usize], to allocate a buffer
+fn to_usize(n: u64, what: &str) -> ReadResult<usize> {
+    std::convert::TryFrom::try_from(n).map_err(|_| {
+        ReadError::InvalidArchive(format!("{} of {} bytes doesn't fit in memory", what, n))
+    })
+}
+
 #[derive(Debug)]
 /// Main struct of this modules, This represent an archive, allows you to read from it;
+///
+/// Reads don't share any cursor, so an [Archive] can be shared between threads and read from
+/// all of them at once
 pub struct Archive {
     file: File,
     headersize: u64,
     filesize: u64,
-    buffer: std::rc::Rc<std::cell::RefCell<std::io::BufReader<std::fs::File>>>,
+    source: Arc<source::Source>,
+    version: u8,
+    /// Built the first time it is needed, as it requires parsing every directory
+    index: OnceLock<PathIndex>,
 }
 
+// Sharing an archive between threads is part of the API, keep it from silently breaking
+const _: fn() = || {
+    fn assert<T: Send + Sync>() {}
+    assert::<Archive>();
+};
+
 impl Archive {
-    /// ID bytes of archive
-    pub const ID: [u8; 4] = *b"KLU\x00";
+    /// ID bytes of archive, the last byte is the newest format version this crate can read
+    pub const ID: [u8; 4] = *b"KLU\x03";
 
-    /// Read an archive from a path
+    /// Read an archive from a path, or the archive appended to the file at this path with
+    /// [crate::write::Archive::append_to_path]
     pub fn from_path<P: AsRef<Path>>(path: P) -> ReadResult<Self> {
-        let mut f = std::io::BufReader::new(std::fs::File::open(path)?);
-        let mut buffer = vec![0x00; 4 + 8 + 8];
-        f.read(&mut buffer)?;
-        if buffer[0..4] != Self::ID {
-            return Err(ReadError::InvalidArchive);
+        Self::from_source(source::Source::File(source::SharedFile::new(
+            std::fs::File::open(path)?,
+        )))
+    }
+
+    /// Read an archive split by [crate::write::Archive::write_volumes_to_path] from its first
+    /// volume, `<name>.001`, the next ones being read along with it
+    pub fn from_volumes<P: AsRef<Path>>(first: P) -> ReadResult<Self> {
+        let first = first.as_ref();
+        // The next volumes are found by numbering from it
+        if first.extension() != Some("001".as_ref()) {
+            return Err(ReadError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} isn't a first volume, ending with .001", first.display()),
+            )));
+        }
+        let archive = Self::from_source(source::Source::volumes(first)?)?;
+        // A missing volume would only be noticed when reading what it holds
+        let len = archive.source.len()?;
+        if archive.version < 3 && archive.filesize != len {
+            return Err(ReadError::InvalidArchive(format!(
+                "the archive is {} bytes but its volumes hold {}",
+                archive.filesize, len
+            )));
+        }
+        Ok(archive)
+    }
+
+    /// Read the archive appended to the running executable, see
+    /// [crate::write::Archive::append_to_path]
+    pub fn from_current_exe() -> ReadResult<Self> {
+        Self::from_path(std::env::current_exe()?)
+    }
+
+    #[cfg(feature = "mmap")]
+    /// Feature: "mmap"
+    ///
+    /// Read an archive from a path by memory-mapping it, reads then don't make any syscall and
+    /// [Archive::bytes] gives the files' data without copying them.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this process or any other, while the
+    /// archive is open: the slices given by [Archive::bytes] would change under the caller, or
+    /// reading them would crash the process
+    #[allow(unsafe_code)]
+    pub unsafe fn from_path_mmap<P: AsRef<Path>>(path: P) -> ReadResult<Self> {
+        let file = std::fs::File::open(path)?;
+        // SAFETY: the map is only read, and the caller guarantees the file isn't modified
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Self::from_source(source::Source::Mmap(map))
+    }
+
+    /// Read an archive held in memory, like one embedded with [include_bytes], without touching
+    /// the filesystem. [Archive::bytes] then gives the files' data without copying them
+    pub fn from_bytes<B: AsRef<[u8]> + Send + Sync + 'static>(bytes: B) -> ReadResult<Self> {
+        Self::from_source(source::Source::Bytes(Box::new(bytes)))
+    }
+
+    fn from_source(source: source::Source) -> ReadResult<Self> {
+        let mut id = [0x00; 4];
+        source.read_exact_at(&mut id, 0)?;
+        // Not an archive itself, but maybe one has been appended to it
+        let source = if id[0..3] != Self::ID[0..3] {
+            let source = source.embedded()?;
+            source.read_exact_at(&mut id, 0)?;
+            source
+        } else {
+            source
+        };
+        if id[0..3] != Self::ID[0..3] || id[3] > Self::ID[3] {
+            return Err(ReadError::InvalidArchive(format!("unknown ID {:?}", id)));
+        }
+        let version = id[3];
+        let (headersize, filesize, root_offset) = if version >= 3 {
+            // Append-only layout: the root's registration is found from the footer
+            let len = source.len()?;
+            let footer_offset = len
+                .checked_sub(utils::FOOTER_LEN)
+                .filter(|offset| *offset >= 4)
+                .ok_or_else(|| ReadError::InvalidArchive("no room for a footer".to_owned()))?;
+            let mut footer = [0x00; utils::FOOTER_LEN as usize];
+            source.read_exact_at(&mut footer, footer_offset)?;
+            if footer[16..] != utils::FOOTER_MAGIC {
+                return Err(ReadError::InvalidArchive(format!(
+                    "unknown footer {:?}",
+                    &footer[16..]
+                )));
+            }
+            let index = utils::slice_to_u64(&footer[0..8]);
+            (utils::slice_to_u64(&footer[8..16]), len, index)
+        } else {
+            let mut buffer = [0x00; 8 + 8];
+            source.read_exact_at(&mut buffer, 4)?;
+            let headersize = utils::slice_to_u64(&buffer[0..8]);
+            (headersize, utils::slice_to_u64(&buffer[8..16]), 4 + 8 + 8)
+        };
+        // Checked before allocating the header, which a corrupted size could make huge
+        let data_offset = checked_add(root_offset, headersize, "root registration")?;
+        if data_offset > source.len()? {
+            return Err(ReadError::InvalidArchive(format!(
+                "root registration of {} bytes goes past the end of the archive",
+                headersize
+            )));
+        }
+        let mut buffer = vec![0; to_usize(headersize, "root registration")?];
+        source.read_exact_at(&mut buffer, root_offset)?;
+        if buffer.is_empty() || utils::header_len(&buffer, version) != buffer.len() {
+            return Err(ReadError::InvalidArchive(format!(
+                "root registration of {} bytes doesn't hold one entry",
+                headersize
+            )));
         }
-        let headersize = utils::slice_to_u64(&buffer[4..(4 + 8)]);
-        let filesize = utils::slice_to_u64(&buffer[(4 + 8)..(4 + 8 + 8)]);
-        buffer = vec![0; headersize as usize];
-        f.read(&mut buffer)?;
 
+        // Only the root's registration is read, directories are parsed when first reached
+        let file = File::from_header(&buffer, data_offset, version, root_offset)?;
+        // Only the root of an archive with several top-level entries has no name
+        if !file.filename.is_empty() {
+            check_name(&file.filename)?;
+        }
+        // Every offset computed below the root is then known to fit in a u64
+        checked_add(file.relative_offset, file.filesize, "root data")?;
         Ok(Archive {
-            file: File::from_header(&buffer, &mut f, 4 + 8 + 8 + headersize)?,
-            buffer: std::rc::Rc::new(std::cell::RefCell::new(f)),
+            file,
+            source: Arc::new(source),
+            version,
+            index: OnceLock::new(),
             headersize,
             filesize,
         })
     }
 
     /// Returns true if a file at given path exists inside the archive
-    pub fn path_exist<P: AsRef<Path>>(&mut self, path: P) -> bool {
-        match self.get_with_path(path) {
-            Some(_) => true,
-            None => false,
+    pub fn path_exist<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.get_with_path(path).is_some()
+    }
+    /// The index of every path of the archive, for bulk lookups
+    ///
+    /// The index is built the first time it is asked for, which parses every directory
+    pub fn index(&self) -> ReadResult<&PathIndex> {
+        if let Some(index) = self.index.get() {
+            return Ok(index);
         }
+        let mut entries = Vec::new();
+        self.file
+            .index_entries(self, "", &mut Vec::new(), &mut Vec::new(), &mut entries)?;
+        Ok(self.index.get_or_init(|| PathIndex::new(entries)))
+    }
+    /// The entry at `path`, which can leave the root's name out as for [Archive::path_exist]
+    ///
+    /// Builds the [Archive::index] to know the entry's indices
+    pub fn entry<P: AsRef<Path>>(&self, path: P) -> ReadResult<Option<Entry>> {
+        let index = self.index()?;
+        let found = PathIndex::key(path).and_then(|key| match index.get(&key) {
+            Some(indices) => Some((key, indices)),
+            None => {
+                let key = self.root_prefixed(&key)?;
+                let indices = index.get(&key)?;
+                Some((key, indices))
+            }
+        });
+        let (key, indices) = match found {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let file = match self.get_with_indices(indices) {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let size = if file.is_sparse {
+            file.sparse_map(&mut self.reader())?.0
+        } else if file.is_file {
+            file.filesize
+        } else {
+            0
+        };
+        let path = if file.is_file {
+            key
+        } else {
+            format!("{}/", key)
+        };
+        Ok(Some(Entry {
+            path,
+            indices: indices.to_vec(),
+            is_file: file.is_file,
+            size,
+        }))
     }
+    /// Only parses the directories on the way, unless the index has already been built
+    ///
+    /// The root's name can be left out of `path`, `textures/a.png` then being looked up as
+    /// `archive/textures/a.png` when it doesn't start with the root's name
     fn get_with_path<P: AsRef<Path>>(&self, path: P) -> Option<&File> {
-        let path = path
-            .as_ref()
-            .iter()
-            .map(|x| &*x.to_str().unwrap())
-            .collect::<Vec<&str>>();
-        let mut f = None;
-        if path[0] == self.file.filename {
-            f = Some(&self.file);
-            for name in &path[1..] {
-                if let Some(file) = f {
-                    let mut flag = false;
-                    for child in &file.child {
-                        if &child.filename == name {
-                            f = Some(child);
-                            flag = true;
-                        }
-                    }
-                    if flag == false {
-                        f = None;
-                    }
-                }
+        self.find(path).ok().flatten()
+    }
+    /// Same as [Archive::get_with_path], failing if a directory on the way is corrupted
+    fn find<P: AsRef<Path>>(&self, path: P) -> ReadResult<Option<&File>> {
+        let key = match PathIndex::key(path) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        if let Some(file) = self.lookup(&key)? {
+            return Ok(Some(file));
+        }
+        match self.root_prefixed(&key) {
+            Some(key) => self.lookup(&key),
+            None => Ok(None),
+        }
+    }
+    /// `key` with the root's name as first component, [None] if the root has no name
+    fn root_prefixed(&self, key: &str) -> Option<String> {
+        if self.file.filename.is_empty() {
+            return None;
+        }
+        Some(format!("{}/{}", self.file.filename, key))
+    }
+    /// The file at `key`, starting with the root's name unless the archive has several
+    /// top-level entries
+    fn lookup(&self, key: &str) -> ReadResult<Option<&File>> {
+        if let Some(index) = self.index.get() {
+            return Ok(index
+                .get(key)
+                .and_then(|indices| self.get_with_indices(indices)));
+        }
+        let mut names = key.split('/');
+        if !self.file.filename.is_empty() && names.next() != Some(&self.file.filename) {
+            return Ok(None);
+        }
+        let mut f = &self.file;
+        for name in names {
+            f = match self.childs(f)?.get(name) {
+                Some(child) => child,
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(f))
+    }
+    fn get_with_indices(&self, indices: &[u32]) -> Option<&File> {
+        let mut f = &self.file;
+        for index in indices {
+            f = self.childs(f).ok()?.childs.get(*index as usize)?;
+        }
+        Some(f)
+    }
+    /// The childs of a directory, its header being parsed the first time
+    fn childs<'a>(&self, file: &'a File) -> ReadResult<&'a Dir> {
+        if let Some(dir) = file.child.get() {
+            return Ok(dir);
+        }
+        let dir = if file.is_file {
+            Dir::default()
+        } else {
+            file.parse_childs(&self.source, self.version)?
+        };
+        Ok(file.child.get_or_init(|| dir))
+    }
+    /// Returns the file a [Handle] points to, without looking its path up
+    fn get_with_handle(&self, handle: &Handle) -> Option<&File> {
+        let f = self.get_with_indices(handle.indices)?;
+        // A handle generated for another archive most likely names another entry
+        if !f.is_file || handle.path.rsplit('/').next() != Some(&f.filename) {
+            return None;
+        }
+        Some(f)
+    }
+    /// Length of the whole archive
+    pub(crate) fn raw_len(&self) -> ReadResult<u64> {
+        Ok(self.source.len()?)
+    }
+    /// Fill `buf` with the bytes of the archive starting at `offset`
+    pub(crate) fn read_raw(&self, buf: &mut [u8], offset: u64) -> ReadResult<()> {
+        Ok(self.source.read_exact_at(buf, offset)?)
+    }
+    /// The offset and stored size of the data of the file at `path`, sparse map included
+    pub(crate) fn data_range<P: AsRef<Path>>(&self, path: P) -> Option<(u64, u64)> {
+        self.get_with_path(path)
+            .filter(|file| file.is_file)
+            .map(|file| (file.relative_offset, file.filesize))
+    }
+    /// True if the archive has been appended to another file
+    pub(crate) fn is_embedded(&self) -> bool {
+        matches!(*self.source, source::Source::Embedded { .. })
+    }
+    /// True if the archive is split into volumes
+    pub(crate) fn is_split(&self) -> bool {
+        matches!(*self.source, source::Source::Volumes { .. })
+    }
+    /// Name of the root, the first component of every path, empty if the archive has several
+    /// top-level entries (see [crate::write::Archive::from_paths])
+    pub fn root_name(&self) -> &str {
+        &self.file.filename
+    }
+    /// Format version of the archive
+    pub(crate) fn version(&self) -> u8 {
+        self.version
+    }
+    /// Length of the root's registration
+    pub(crate) fn headersize(&self) -> u64 {
+        self.headersize
+    }
+    /// The root's registration
+    pub(crate) fn raw_root(&self) -> RawEntry {
+        self.file.raw()
+    }
+    /// The registrations from the root down to the entry at `path`, [None] if there is none.
+    /// Looked up as by [Archive::get_with_path], an empty path being the root
+    pub(crate) fn raw_path<P: AsRef<Path>>(&self, path: P) -> ReadResult<Option<Vec<RawEntry>>> {
+        let key = match PathIndex::key(path) {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        if key.is_empty() {
+            return Ok(Some(vec![self.file.raw()]));
+        }
+        match self.raw_chain(&key)? {
+            Some(chain) => Ok(Some(chain)),
+            None => match self.root_prefixed(&key) {
+                Some(key) => self.raw_chain(&key),
+                None => Ok(None),
+            },
+        }
+    }
+    /// Same as [Archive::lookup], returning the registrations on the way
+    fn raw_chain(&self, key: &str) -> ReadResult<Option<Vec<RawEntry>>> {
+        let mut names = key.split('/');
+        if !self.file.filename.is_empty() && names.next() != Some(&self.file.filename) {
+            return Ok(None);
+        }
+        let mut f = &self.file;
+        let mut chain = vec![f.raw()];
+        for name in names {
+            f = match self.childs(f)?.get(name) {
+                Some(child) => child,
+                None => return Ok(None),
+            };
+            chain.push(f.raw());
+        }
+        Ok(Some(chain))
+    }
+    /// The registrations of the childs of the directory `dir`, read from its data even if it has
+    /// been written since the archive was opened
+    pub(crate) fn raw_dir(&self, dir: &RawEntry) -> ReadResult<Vec<RawEntry>> {
+        let file = File {
+            filename: dir.name.clone(),
+            filesize: dir.size,
+            is_file: false,
+            is_sparse: false,
+            child: OnceLock::new(),
+            relative_offset: dir.offset,
+            record_offset: dir.record_offset,
+            has_offset: dir.has_offset,
+        };
+        checked_add(dir.offset, dir.size, &dir.name)?;
+        let childs = file.parse_childs(&self.source, self.version)?.childs;
+        Ok(childs.iter().map(File::raw).collect())
+    }
+    /// The path, offset and stored size of the data of every file, sorted by offset
+    pub(crate) fn data_ranges(&self) -> ReadResult<Vec<(String, u64, u64)>> {
+        let mut ranges = Vec::new();
+        for entry in self.entries()? {
+            if entry.is_file {
+                let file = self
+                    .get_with_indices(&entry.indices)
+                    .expect("Entries are only listed once parsed");
+                ranges.push((entry.path, file.relative_offset, file.filesize));
+            }
+        }
+        ranges.sort_by_key(|(_, offset, _)| *offset);
+        Ok(ranges)
+    }
+
+    /// A reader over the data of the file at `path`, holes of sparse files reading as zeros
+    pub(crate) fn data_reader<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> ReadResult<Option<DataReader<'_>>> {
+        let file = match self.find(path)? {
+            Some(file) if file.is_file => file,
+            _ => return Ok(None),
+        };
+        let mut parts = std::collections::VecDeque::new();
+        if !file.is_sparse {
+            parts.push_back((Some(file.relative_offset), file.filesize));
+        } else {
+            let (apparent_size, extents) = file.sparse_map(&mut self.reader())?;
+            // Checked against the stored size by `sparse_map`
+            let mut data = file.relative_offset + 16 + 16 * extents.len() as u64;
+            let mut written = 0;
+            for (offset, length) in extents {
+                parts.push_back((None, offset - written));
+                parts.push_back((Some(data), length));
+                data += length;
+                written = offset + length;
             }
+            parts.push_back((None, apparent_size - written));
         }
-        f
+        Ok(Some(DataReader {
+            source: &self.source,
+            parts,
+        }))
+    }
+    /// Every entry of the archive with its size, each directory listed before its childs
+    ///
+    /// This parses every directory
+    pub fn entries(&self) -> ReadResult<Vec<Entry>> {
+        let mut entries = Vec::new();
+        self.file.entries(
+            self,
+            &mut self.reader(),
+            "",
+            &mut Vec::new(),
+            &mut Vec::new(),
+            &mut entries,
+        )?;
+        Ok(entries)
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A precomputed reference to a file of an archive, usually generated at build time by
+/// [crate::build::generate_constants]. Opening a file from its handle skips the path lookup
+pub struct Handle {
+    /// Path of the file inside the archive
+    pub path: &'static str,
+    /// Size of the file, holes of sparse files included
+    pub size: u64,
+    /// Index of the entry among its parent's childs, for each level below the root
+    pub indices: &'static [u32],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An entry of the archive, as listed by [Archive::entries]
+pub struct Entry {
+    /// Path inside the archive, ending with a `/` for directories
+    pub path: String,
+    /// Same as [Handle::indices]
+    pub indices: Vec<u32>,
+    /// True for files, false for directories
+    pub is_file: bool,
+    /// Same as [Handle::size], 0 for directories
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+/// A file registration as stored, for [crate::edit]
+pub(crate) struct RawEntry {
+    pub(crate) name: String,
+    pub(crate) is_file: bool,
+    pub(crate) is_sparse: bool,
+    /// Stored size of the data, sparse map included
+    pub(crate) size: u64,
+    /// Absolute offset of the data
+    pub(crate) offset: u64,
+    /// Absolute offset of the registration
+    pub(crate) record_offset: u64,
+    /// True if the registration holds `offset`, see [utils::FLAG_OFFSET]
+    pub(crate) has_offset: bool,
+}
+
 #[derive(Debug, Clone)]
 struct File {
     filename: String,
     filesize: u64,
     is_file: bool,
-    child: Vec<Self>,
+    is_sparse: bool,
+    /// Parsed the first time it is needed, see [Archive::childs]
+    child: OnceLock<Dir>,
     relative_offset: u64,
+    /// Absolute offset of the file's registration
+    record_offset: u64,
+    /// True if the registration gives the offset of the data, see [utils::FLAG_OFFSET]
+    has_offset: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Dir {
+    childs: Vec<File>,
+    /// Indices of `childs`, sorted by name
+    by_name: Vec<u32>,
+}
+
+impl Dir {
+    fn get(&self, name: &str) -> Option<&File> {
+        self.by_name
+            .binary_search_by(|i| self.childs[*i as usize].filename.as_str().cmp(name))
+            .ok()
+            .map(|i| &self.childs[self.by_name[i] as usize])
+    }
 }
 
 impl File {
+    fn raw(&self) -> RawEntry {
+        RawEntry {
+            name: self.filename.clone(),
+            is_file: self.is_file,
+            is_sparse: self.is_sparse,
+            size: self.filesize,
+            offset: self.relative_offset,
+            record_offset: self.record_offset,
+            has_offset: self.has_offset,
+        }
+    }
+
+    /// Read a file registration found at `record_offset`, the data of the file starting at
+    /// `offset` unless the registration gives its own
     fn from_header(
         header: &[u8],
-        r_buf: &mut std::io::BufReader<std::fs::File>,
         offset: u64,
+        version: u8,
+        record_offset: u64,
     ) -> ReadResult<Self> {
-        let (flag, file_size, file_name) = utils::parse_header(header);
-        let mut buffer = vec![0_u8; 8];
-        let mut childs = Vec::new();
-        if !flag {
-            r_buf.read(&mut buffer)?;
-            let h_size = utils::slice_to_u64(&buffer);
-            buffer = vec![0x00; h_size as usize];
-            r_buf.read(&mut buffer)?;
-            let mut current_offset = offset + 8 + h_size;
-            while !buffer.is_empty() {
-                let c_header_size = (buffer[0] >> 1) as usize + 8 + 1;
-                let c_header = utils::split_in_place(&mut buffer, c_header_size);
-                let f = Self::from_header(&c_header, r_buf, current_offset)?;
-                current_offset += f.filesize;
-                childs.push(f);
-            }
-        }
+        let (flag, flags, file_size, data_offset, file_name) = utils::parse_header(header, version)
+            .map_err(|_| {
+                ReadError::InvalidArchive(format!(
+                    "name registered at offset {} isn't valid UTF-8",
+                    record_offset
+                ))
+            })?;
         Ok(File {
             filename: file_name,
             filesize: file_size,
             is_file: flag,
-            child: childs,
-            relative_offset: offset,
+            is_sparse: flag && flags & utils::FLAG_SPARSE != 0,
+            child: OnceLock::new(),
+            relative_offset: data_offset.unwrap_or(offset),
+            record_offset,
+            has_offset: data_offset.is_some(),
         })
     }
+
+    /// Parse the header of this directory, found at the start of its data
+    fn parse_childs(&self, source: &source::Source, version: u8) -> ReadResult<Dir> {
+        let mut buffer = vec![0_u8; 8];
+        source.read_exact_at(&mut buffer, self.relative_offset)?;
+        let h_size = utils::slice_to_u64(&buffer);
+        if self.filesize < 8 || h_size > self.filesize - 8 {
+            return Err(ReadError::InvalidArchive(format!(
+                "header of {} bytes doesn't fit in directory {}",
+                h_size, self.filename
+            )));
+        }
+        // The directory's data has been checked to end before u64::MAX by its parent
+        buffer = vec![0x00; to_usize(h_size, "directory header")?];
+        source.read_exact_at(&mut buffer, self.relative_offset + 8)?;
+        let mut childs = Vec::new();
+        let mut current_offset = self.relative_offset + 8 + h_size;
+        // Registrations are read in place, `pos` being the start of the next one
+        let mut pos = 0;
+        while pos < buffer.len() {
+            let c_header_size = utils::header_len(&buffer[pos..], version);
+            if c_header_size > buffer.len() - pos {
+                return Err(ReadError::InvalidArchive(format!(
+                    "truncated registration in directory {}",
+                    self.filename
+                )));
+            }
+            let f = Self::from_header(
+                &buffer[pos..pos + c_header_size],
+                current_offset,
+                version,
+                self.relative_offset + 8 + pos as u64,
+            )?;
+            check_name(&f.filename)?;
+            pos += c_header_size;
+            // Data stored elsewhere isn't part of this directory's
+            if f.has_offset {
+                checked_add(f.relative_offset, f.filesize, &f.filename)?;
+            } else {
+                current_offset = checked_add(current_offset, f.filesize, &f.filename)?;
+            }
+            childs.push(f);
+        }
+        // The childs' data must fill the directory's, else every offset computed is wrong
+        if current_offset != self.relative_offset + self.filesize {
+            return Err(ReadError::InvalidArchive(format!(
+                "childs of directory {} hold {} bytes instead of {}",
+                self.filename,
+                current_offset - self.relative_offset,
+                self.filesize
+            )));
+        }
+        if childs.len() > u32::MAX as usize {
+            return Err(ReadError::InvalidArchive(format!(
+                "too many childs in directory {}",
+                self.filename
+            )));
+        }
+        let mut by_name = (0..childs.len() as u32).collect::<Vec<_>>();
+        by_name.sort_unstable_by(|a, b| {
+            childs[*a as usize]
+                .filename
+                .cmp(&childs[*b as usize].filename)
+        });
+        Ok(Dir { childs, by_name })
+    }
+
+    /// Push the path of this file and of all its childs, with their indices, onto `out`
+    ///
+    /// `ancestors` holds the data offsets of the directories this file is in, see [enter_dir]
+    fn index_entries(
+        &self,
+        archive: &Archive,
+        base: &str,
+        indices: &mut Vec<u32>,
+        ancestors: &mut Vec<u64>,
+        out: &mut Vec<(String, Box<[u32]>)>,
+    ) -> ReadResult<()> {
+        let path = format!("{}{}", base, self.filename);
+        // Only the root of an archive with several top-level entries has no name
+        let base = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{}/", path)
+        };
+        if !self.is_file {
+            enter_dir(ancestors, self.relative_offset, &self.filename)?;
+            for (index, child) in archive.childs(self)?.childs.iter().enumerate() {
+                indices.push(index as u32);
+                child.index_entries(archive, &base, indices, ancestors, out)?;
+                indices.pop();
+            }
+            ancestors.pop();
+        }
+        if !path.is_empty() {
+            out.push((path, indices.clone().into_boxed_slice()));
+        }
+        Ok(())
+    }
+
+    /// Push the entry of this file and those of all its childs onto `out`, `ancestors` being as
+    /// for [File::index_entries]
+    fn entries(
+        &self,
+        archive: &Archive,
+        reader: &mut ArchiveReader,
+        base: &str,
+        indices: &mut Vec<u32>,
+        ancestors: &mut Vec<u64>,
+        out: &mut Vec<Entry>,
+    ) -> ReadResult<()> {
+        // The root of an archive with several top-level entries isn't listed
+        if self.filename.is_empty() {
+            enter_dir(ancestors, self.relative_offset, &self.filename)?;
+            for (index, child) in archive.childs(self)?.childs.iter().enumerate() {
+                indices.push(index as u32);
+                child.entries(archive, reader, "", indices, ancestors, out)?;
+                indices.pop();
+            }
+            ancestors.pop();
+            return Ok(());
+        }
+        let path = format!(
+            "{}{}{}",
+            base,
+            self.filename,
+            if self.is_file { "" } else { "/" }
+        );
+        let size = if self.is_sparse {
+            self.sparse_map(reader)?.0
+        } else if self.is_file {
+            self.filesize
+        } else {
+            0
+        };
+        out.push(Entry {
+            path: path.clone(),
+            indices: indices.clone(),
+            is_file: self.is_file,
+            size,
+        });
+        if !self.is_file {
+            enter_dir(ancestors, self.relative_offset, &self.filename)?;
+            for (index, child) in archive.childs(self)?.childs.iter().enumerate() {
+                indices.push(index as u32);
+                child.entries(archive, reader, &path, indices, ancestors, out)?;
+                indices.pop();
+            }
+            ancestors.pop();
+        }
+        Ok(())
+    }
+
+    /// Read the sparse map of this file, leaving `archive` at the start of the extents' data
+    /// Returns the apparent size and the `(offset, length)` of every extent
+    fn sparse_map(&self, archive: &mut ArchiveReader) -> ReadResult<(u64, Vec<(u64, u64)>)> {
+        let mut buffer = [0_u8; 16];
+        archive.seek(SeekFrom::Start(self.relative_offset))?;
+        archive.read_exact(&mut buffer)?;
+        let apparent_size = utils::slice_to_u64(&buffer[0..8]);
+        let count = utils::slice_to_u64(&buffer[8..16]);
+        let mut extents = Vec::new();
+        let mut stored = 16;
+        let mut previous_end = 0;
+        for _ in 0..count {
+            archive.read_exact(&mut buffer)?;
+            let (offset, length) = (
+                utils::slice_to_u64(&buffer[0..8]),
+                utils::slice_to_u64(&buffer[8..16]),
+            );
+            let end = checked_add(offset, length, &self.filename)?;
+            if end > apparent_size || offset < previous_end {
+                return Err(ReadError::InvalidArchive(format!(
+                    "extent of {} out of order or past its size",
+                    self.filename
+                )));
+            }
+            previous_end = end;
+            stored = checked_add(
+                checked_add(stored, 16, &self.filename)?,
+                length,
+                &self.filename,
+            )?;
+            // A corrupted count would otherwise have it read way past the file
+            if stored > self.filesize {
+                break;
+            }
+            extents.push((offset, length));
+        }
+        if stored != self.filesize {
+            return Err(ReadError::InvalidArchive(format!(
+                "sparse map of {} doesn't match its stored size",
+                self.filename
+            )));
+        }
+        Ok((apparent_size, extents))
+    }
 }
 // Things that help the user, like locating a file with his path...
 /// User's function for using an [Archive]
 impl Archive {
     /// Extract all archive's content onto a directory
-    pub fn release<P: AsRef<Path>>(&mut self, path: P) -> ReadResult<()> {
+    pub fn release<P: AsRef<Path>>(&self, path: P) -> ReadResult<()> {
         if !path.as_ref().exists() {
             return Err(ReadError::InexistantOut);
         }
+        if !self.file.filename.is_empty() {
+            check_name(&self.file.filename)?;
+        }
         let path = path.as_ref().join(self.file.filename.clone());
         self.file
-            .write_to_path(&mut self.buffer.try_borrow_mut().unwrap(), path)
+            .write_to_path(self, &mut self.reader(), &mut Vec::new(), path)
+    }
+    fn reader(&self) -> ArchiveReader<'_> {
+        std::io::BufReader::new(self.source.reader(0))
     }
     /// Return a `[Vec<String>]` with all files inside the archive
+    ///
+    /// Directories whose header can't be read are listed without their content, use
+    /// [Archive::index] to get the error
     pub fn paths(&self) -> Vec<String> {
         let mut p = Vec::new();
+        // The root of an archive with several top-level entries isn't listed
+        if self.file.filename.is_empty() {
+            self.file
+                .paths(self, &mut p, &mut Vec::new(), String::new());
+            return p;
+        }
         p.push(format!(
             "{}{}",
             self.file.filename,
             if self.file.is_file { "" } else { "/" }
         ));
         self.file.paths(
+            self,
             &mut p,
+            &mut Vec::new(),
             format!(
                 "{}{}",
                 self.file.filename,
@@ -213,46 +960,148 @@ impl Archive {
     }
     /// Extract a single file from the archive
     /// Returns true if the file exists inside the archive, false otherwise
-    pub fn extract_file<P: AsRef<Path>>(&mut self, path: P, out: P) -> ReadResult<bool> {
-        if let Some(file) = self.get_with_path(path) {
+    pub fn extract_file<P: AsRef<Path>>(&self, path: P, out: P) -> ReadResult<bool> {
+        if let Some(file) = self.find(path)? {
             let mut out = out.as_ref().to_path_buf();
             if !file.is_file {
+                check_name(&file.filename)?;
                 out = out.join(&file.filename);
-                std::fs::create_dir(&out)?;
+                std::fs::create_dir_all(&out)?;
             }
-            file.write_to_path(&mut self.buffer.try_borrow_mut().unwrap(), out)?;
+            file.write_to_path(self, &mut self.reader(), &mut Vec::new(), out)?;
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
+    /// Write the data of the file at the given path to `out`, holes of sparse files as zeros
+    /// Returns true if there is such a file inside the archive, false otherwise (or if it is a
+    /// directory). Fails if a directory on the way to it is corrupted
+    pub fn extract_to<P: AsRef<Path>, W: Write>(&self, path: P, out: &mut W) -> ReadResult<bool> {
+        match self.find(path)? {
+            Some(file) if file.is_file => {
+                file.copy_to(&mut self.reader(), out)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Returns the data of the file at the given path, borrowed from the archive without any copy
+    ///
+    /// Returns [None] if there is no such file, if it is a directory or a sparse file, or if the
+    /// archive isn't in memory (see [Archive::from_bytes] and `Archive::from_path_mmap`)
+    pub fn bytes<P: AsRef<Path>>(&self, path: P) -> Option<&[u8]> {
+        self.file_bytes(self.get_with_path(path)?)
+    }
+    /// Same as [Archive::bytes], for the file a [Handle] points to
+    pub fn handle_bytes(&self, handle: &Handle) -> Option<&[u8]> {
+        self.file_bytes(self.get_with_handle(handle)?)
+    }
+    fn file_bytes(&self, file: &File) -> Option<&[u8]> {
+        if !file.is_file || file.is_sparse {
+            return None;
+        }
+        self.source.slice(file.relative_offset, file.filesize)
+    }
+
     #[cfg(feature = "virtual_fs")]
     /// If the path given match a file , returns a [Some(VirtualFile)], else, return [None]
     /// You can have as many [VirtualFile] as you want, even multiples pointing to the same "file",
     /// as they are independend
-    pub fn get_virtual<P: AsRef<Path>>(&mut self, path: P) -> Option<VirtualFile> {
-        let sizes = match self.get_with_path(path) {
-            Some(f) => Some((f.relative_offset as usize, f.filesize as usize)),
-            None => None,
-        };
-        if sizes == None {
-            return None;
+    /// Sparse files read back as zeros inside their holes
+    pub fn get_virtual<P: AsRef<Path>>(&self, path: P) -> Option<VirtualFile> {
+        self.virtual_file(self.get_with_path(path)?)
+    }
+
+    #[cfg(feature = "virtual_fs")]
+    /// Same as [Archive::get_virtual], for the file a [Handle] points to
+    pub fn open_handle(&self, handle: &Handle) -> Option<VirtualFile> {
+        self.virtual_file(self.get_with_handle(handle)?)
+    }
+
+    #[cfg(feature = "virtual_fs")]
+    fn virtual_file(&self, file: &File) -> Option<VirtualFile> {
+        if !file.is_sparse {
+            return Some(VirtualFile::from_sizes(
+                (file.relative_offset, file.filesize),
+                Arc::clone(&self.source),
+            ));
         }
-        Some(VirtualFile::from_sizes(
-            sizes.unwrap(),
-            std::rc::Rc::clone(&self.buffer),
-        ))
+        let (apparent_size, extents) = file.sparse_map(&mut self.reader()).ok()?;
+        let mut data_offset = 0;
+        let extents = extents
+            .into_iter()
+            .map(|(offset, length)| {
+                data_offset += length;
+                (offset, length, data_offset - length)
+            })
+            .collect::<Vec<_>>();
+        // Checked against the stored size by `sparse_map`
+        let map_len = 16 + 16 * extents.len() as u64;
+        let mut virtual_file = VirtualFile::from_sizes(
+            (file.relative_offset + map_len, apparent_size),
+            Arc::clone(&self.source),
+        );
+        virtual_file.extents = Some(Arc::from(extents));
+        Some(virtual_file)
     }
 }
 
 impl File {
+    /// Write the data of this file to `out`
+    fn copy_to<W: Write>(&self, reader: &mut ArchiveReader, out: &mut W) -> ReadResult<()> {
+        if !self.is_sparse {
+            reader.seek(SeekFrom::Start(self.relative_offset))?;
+            return self.copy_exact(reader, out, self.filesize);
+        }
+        let (apparent_size, extents) = self.sparse_map(reader)?;
+        let mut written = 0;
+        for (offset, length) in extents {
+            std::io::copy(&mut std::io::repeat(0).take(offset - written), out)?;
+            self.copy_exact(reader, out, length)?;
+            written = offset + length;
+        }
+        std::io::copy(&mut std::io::repeat(0).take(apparent_size - written), out)?;
+        Ok(())
+    }
+
+    /// Copy exactly `length` bytes from `reader`, failing if the archive ends before
+    fn copy_exact<W: Write>(
+        &self,
+        reader: &mut ArchiveReader,
+        out: &mut W,
+        length: u64,
+    ) -> ReadResult<()> {
+        if std::io::copy(&mut reader.take(length), out)? != length {
+            return Err(ReadError::InvalidArchive(format!(
+                "data of {} is cut short",
+                self.filename
+            )));
+        }
+        Ok(())
+    }
+
+    /// Write this file, or this directory and its childs, at `output`, `ancestors` being as for
+    /// [File::index_entries]
     fn write_to_path<P: AsRef<Path>>(
         &self,
-        archive: &mut std::io::BufReader<std::fs::File>,
+        archive: &Archive,
+        reader: &mut ArchiveReader,
+        ancestors: &mut Vec<u64>,
         output: P,
     ) -> ReadResult<()> {
-        if self.is_file {
+        if self.is_sparse {
+            let (apparent_size, extents) = self.sparse_map(reader)?;
+            let mut file = std::fs::File::create(&output)?;
+            // Growing the file without writing leaves a hole on filesystems that support them
+            file.set_len(apparent_size)?;
+            for (offset, length) in extents {
+                file.seek(SeekFrom::Start(offset))?;
+                self.copy_exact(reader, &mut file, length)?;
+            }
+        } else if self.is_file {
             let mut file = std::fs::File::create(&output)?;
 
             let mut remaing = self.filesize;
@@ -264,10 +1113,10 @@ impl File {
                     remaing as usize
                 }
             ];
-            archive.seek(std::io::SeekFrom::Start(self.relative_offset))?;
+            reader.seek(SeekFrom::Start(self.relative_offset))?;
             while remaing > 0 {
-                archive.read(&mut buffer)?;
-                file.write(&buffer)?;
+                reader.read_exact(&mut buffer)?;
+                file.write_all(&buffer)?;
                 remaing -= buffer.len() as u64;
                 buffer = vec![
                     0;
@@ -279,24 +1128,87 @@ impl File {
                 ];
             }
         } else {
+            enter_dir(ancestors, self.relative_offset, &self.filename)?;
             if !output.as_ref().exists() {
                 std::fs::create_dir(&output)?;
             }
-            for child in &self.child {
-                child.write_to_path(archive, output.as_ref().join(child.filename.clone()))?;
+            for child in &archive.childs(self)?.childs {
+                check_name(&child.filename)?;
+                child.write_to_path(
+                    archive,
+                    reader,
+                    ancestors,
+                    output.as_ref().join(child.filename.clone()),
+                )?;
             }
+            ancestors.pop();
         }
         Ok(())
     }
-    fn paths(&self, v: &mut Vec<String>, base: String) {
-        for file in &self.child {
+    /// Push the paths below this file onto `v`, `ancestors` being as for [File::index_entries]
+    fn paths(
+        &self,
+        archive: &Archive,
+        v: &mut Vec<String>,
+        ancestors: &mut Vec<u64>,
+        base: String,
+    ) {
+        if enter_dir(ancestors, self.relative_offset, &self.filename).is_err() {
+            return;
+        }
+        let childs = match archive.childs(self) {
+            Ok(dir) => &dir.childs,
+            Err(_) => &[][..],
+        };
+        for file in childs {
             if !file.is_file {
                 v.push(format!("{}{}/", base, file.filename));
-                file.paths(v, format!("{}{}/", base, file.filename));
+                file.paths(archive, v, ancestors, format!("{}{}/", base, file.filename));
             } else {
                 v.push(format!("{}{}", base, file.filename));
             }
         }
+        ancestors.pop();
+    }
+}
+
+/// Reads the data of a file sequentially, see [Archive::data_reader]
+#[derive(Debug)]
+pub(crate) struct DataReader<'a> {
+    source: &'a source::Source,
+    /// The parts of the file left to read, the first one being read: the offset of their data
+    /// in the archive, [None] for holes, and their length
+    parts: std::collections::VecDeque<(Option<u64>, u64)>,
+}
+
+impl Read for DataReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while let Some((data, left)) = self.parts.front_mut() {
+            if *left == 0 {
+                self.parts.pop_front();
+                continue;
+            }
+            let n = buf
+                .len()
+                .min(std::convert::TryFrom::try_from(*left).unwrap_or(usize::MAX));
+            let n = match data {
+                Some(offset) => {
+                    let n = self.source.read_at(&mut buf[..n], *offset)?;
+                    if n == 0 && !buf.is_empty() {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                    *offset += n as u64;
+                    n
+                }
+                None => {
+                    buf[..n].iter_mut().for_each(|b| *b = 0);
+                    n
+                }
+            };
+            *left -= n as u64;
+            return Ok(n);
+        }
+        Ok(0)
     }
 }
 
@@ -304,18 +1216,30 @@ impl File {
 #[derive(Debug, Clone)]
 /// Feature: "virtual_fs"
 ///
-/// This represent a file from the archive, it implements [Read] and [Seek] so it can be used with
-/// a lot of io-based functions
-/// If you need something with [BufRead], just wrap a [std::io::BufReader] around an [VirtualFile]
+/// This represent a file from the archive, it implements [Read], [BufRead] and [Seek] so it can
+/// be used with a lot of io-based functions. It behaves like a [std::fs::File] opened read-only:
+/// seeking past the end is allowed and reads there return 0 bytes, seeking before the start fails
+///
+/// A [VirtualFile] is [Send] and [Sync], and reading it never moves another one's position
 pub struct VirtualFile {
-    buffer: std::rc::Rc<std::cell::RefCell<std::io::BufReader<std::fs::File>>>,
-    start_offset: usize,
-    end_offset: usize,
-    current_offset: usize,
+    source: Arc<source::Source>,
+    start_offset: u64,
+    /// Size of the file, holes of sparse files included
+    len: u64,
+    /// Position in the file of the next byte returned to the user
+    current_offset: u64,
+    /// For sparse files: (offset in the file, length, offset of the data from `start_offset`)
+    extents: Option<Arc<[(u64, u64, u64)]>>,
+    /// Bytes read ahead, `buffer[consumed..]` being the ones at `current_offset`
+    buffer: Vec<u8>,
+    consumed: usize,
 }
 
 #[cfg(feature = "virtual_fs")]
 impl VirtualFile {
+    /// Size of the internal buffer, reads at least as big bypass it
+    const BUFFER_SIZE: usize = 8 * 1024;
+
     /*
     fn from_file(f: &File, b: &'a mut std::io::BufReader<std::fs::File>) -> Self {
         VirtualFile {
@@ -325,91 +1249,150 @@ impl VirtualFile {
             current_offset: 0,
         }
     }*/
-    fn from_sizes(
-        s: (usize, usize),
-        b: std::rc::Rc<std::cell::RefCell<std::io::BufReader<std::fs::File>>>,
-    ) -> Self {
+    fn from_sizes(s: (u64, u64), source: Arc<source::Source>) -> Self {
         VirtualFile {
-            buffer: b,
+            source,
             start_offset: s.0,
-            end_offset: s.0 + s.1,
+            len: s.1,
             current_offset: 0,
+            extents: None,
+            buffer: Vec::new(),
+            consumed: 0,
         }
     }
-    /// Get the file's data
+    /// Size of the file, holes of sparse files included
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+    /// Returns true if the file is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Get the file's data, borrowed from the archive without any copy
+    ///
+    /// Returns [None] for sparse files, or if the archive isn't in memory
+    /// (see [Archive::from_bytes] and `Archive::from_path_mmap`)
+    pub fn bytes(&self) -> Option<&[u8]> {
+        if self.extents.is_some() {
+            return None;
+        }
+        self.source.slice(self.start_offset, self.len)
+    }
+    /// Get the file's data, see [VirtualFile::bytes] to avoid the copy
     pub fn get_slice(&mut self) -> std::io::Result<Box<[u8]>> {
-        let mut buf = vec![0; self.end_offset - self.start_offset];
-        self.read(&mut buf)?;
+        let len = std::convert::TryFrom::try_from(self.len).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::OutOfMemory,
+                "File doesn't fit in memory",
+            )
+        })?;
+        let mut buf = vec![0; len];
+        self.read_exact(&mut buf)?;
         Ok(buf.into_boxed_slice())
     }
+
+    /// Read the bytes at `pos` in the file into `buffer`, without going through the internal one
+    fn read_at(&self, buffer: &mut [u8], pos: u64) -> std::io::Result<usize> {
+        // Sizes are kept in u64 until they are known to be at most `buffer.len()`
+        let mut nbuf_size = (buffer.len() as u64).min(self.len.saturating_sub(pos));
+        if nbuf_size == 0 {
+            return Ok(0);
+        }
+        let mut data_offset = pos;
+        if let Some(extents) = &self.extents {
+            match extents
+                .iter()
+                .find(|(offset, length, _)| offset + length > pos)
+            {
+                Some((offset, length, data)) if *offset <= pos => {
+                    nbuf_size = nbuf_size.min(offset + length - pos);
+                    data_offset = data + pos - offset;
+                }
+                next => {
+                    // Inside a hole, which reads as zeros up to the next extent
+                    if let Some((offset, _, _)) = next {
+                        nbuf_size = nbuf_size.min(offset - pos);
+                    }
+                    let nbuf_size = nbuf_size as usize;
+                    buffer[0..nbuf_size].iter_mut().for_each(|b| *b = 0);
+                    return Ok(nbuf_size);
+                }
+            }
+        }
+        let nbuf_size = nbuf_size as usize;
+        self.source
+            .read_exact_at(&mut buffer[0..nbuf_size], self.start_offset + data_offset)?;
+        Ok(nbuf_size)
+    }
 }
 
 #[cfg(feature = "virtual_fs")]
 impl Read for VirtualFile {
     fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
-        let mut a_buf = self.buffer.try_borrow_mut().unwrap();
-        a_buf.seek(std::io::SeekFrom::Start(
-            (self.start_offset + self.current_offset) as u64,
-        ))?;
-        let bytes_left = self.end_offset - self.start_offset - self.current_offset;
-        let buffer_len = buffer.len();
-        let nbuf_size = if buffer_len < bytes_left {
-            buffer_len
-        } else {
-            bytes_left
-        };
-        if bytes_left == 0 || buffer.len() == 0 {
-            return Ok(0);
+        // Nothing to gain from copying big reads through the buffer
+        if self.consumed == self.buffer.len() && buffer.len() >= Self::BUFFER_SIZE {
+            let n = self.read_at(buffer, self.current_offset)?;
+            self.current_offset += n as u64;
+            return Ok(n);
         }
-        a_buf.read(&mut buffer[0..nbuf_size])?;
-        self.current_offset += nbuf_size;
-        Ok(nbuf_size)
+        let available = self.fill_buf()?;
+        let n = available.len().min(buffer.len());
+        buffer[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "virtual_fs")]
+impl BufRead for VirtualFile {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        if self.consumed == self.buffer.len() {
+            let mut buffer = std::mem::take(&mut self.buffer);
+            buffer.resize(Self::BUFFER_SIZE, 0);
+            let read = self.read_at(&mut buffer, self.current_offset);
+            // On error, leave the buffer empty rather than filled with garbage
+            buffer.truncate(*read.as_ref().unwrap_or(&0));
+            self.buffer = buffer;
+            self.consumed = 0;
+            read?;
+        }
+        Ok(&self.buffer[self.consumed..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = amt.min(self.buffer.len() - self.consumed);
+        self.consumed += amt;
+        self.current_offset += amt as u64;
     }
 }
-//Os { code: 22, kind: InvalidInput, message: "Invalid argument" }
 
 #[cfg(feature = "virtual_fs")]
 impl Seek for VirtualFile {
-    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
-        use std::io::SeekFrom;
-        match pos {
-            SeekFrom::Start(n) => {
-                self.current_offset = n as usize;
-                if self.current_offset + self.start_offset > self.end_offset {
-                    self.current_offset = self.end_offset;
-                }
-            }
-            SeekFrom::Current(n) => {
-                if (self.current_offset as i64) < n {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        "Invalid argument",
-                    ));
-                } else {
-                    if (self.current_offset + self.start_offset) as i64 + n > self.end_offset as i64
-                    {
-                        self.current_offset = self.end_offset;
-                    }
-                }
-            }
-            SeekFrom::End(n) => {
-                if (self.start_offset as i64) > self.end_offset as i64 - n {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidInput,
-                        "Invalid argument",
-                    ));
-                }
-                if n >= 0 {
-                    self.current_offset = self.end_offset;
-                } else {
-                    self.current_offset = self.end_offset - n as usize;
-                }
-            }
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        // Positions are limited to i64::MAX, as with a file
+        let pos = match pos {
+            SeekFrom::Start(n) => std::convert::TryFrom::try_from(n).ok(),
+            SeekFrom::Current(n) => (self.current_offset as i64).checked_add(n),
+            SeekFrom::End(n) => std::convert::TryFrom::try_from(self.len)
+                .ok()
+                .and_then(|len: i64| len.checked_add(n)),
         }
-        let mut buffer = self.buffer.try_borrow_mut().unwrap();
-        buffer.seek(std::io::SeekFrom::Start(
-            (self.current_offset + self.start_offset) as u64,
-        ))?;
-        Ok(self.current_offset as u64)
+        .filter(|pos| *pos >= 0)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid argument"))?
+            as u64;
+        // Keep the buffered bytes if the new position is among them
+        let buffer_start = self.current_offset - self.consumed as u64;
+        if buffer_start <= pos && pos <= buffer_start + self.buffer.len() as u64 {
+            self.consumed = (pos - buffer_start) as usize;
+        } else {
+            self.buffer.clear();
+            self.consumed = 0;
+        }
+        self.current_offset = pos;
+        Ok(pos)
+    }
+
+    fn stream_position(&mut self) -> std::io::Result<u64> {
+        Ok(self.current_offset)
     }
 }
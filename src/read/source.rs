@@ -0,0 +1,253 @@
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// The bytes of an archive, read with positional reads so it can be shared between threads
+pub enum Source {
+    File(SharedFile),
+    #[cfg(feature = "mmap")]
+    Mmap(memmap2::Mmap),
+    Bytes(Box<dyn AsRef<[u8]> + Send + Sync>),
+    /// An archive split into volumes, each file holding the bytes from its start up to the next
+    /// one's
+    Volumes {
+        files: Vec<SharedFile>,
+        starts: Vec<u64>,
+        len: u64,
+    },
+    /// An archive appended to another file, the `len` bytes at `start` of `inner`
+    Embedded {
+        inner: Box<Source>,
+        start: u64,
+        len: u64,
+    },
+}
+
+impl std::fmt::Debug for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::File(file) => f.debug_tuple("File").field(file).finish(),
+            #[cfg(feature = "mmap")]
+            Self::Mmap(map) => f.debug_tuple("Mmap").field(map).finish(),
+            Self::Bytes(bytes) => write!(f, "Bytes({} bytes)", (**bytes).as_ref().len()),
+            Self::Volumes { files, len, .. } => f
+                .debug_struct("Volumes")
+                .field("files", files)
+                .field("len", len)
+                .finish(),
+            Self::Embedded { inner, start, len } => f
+                .debug_struct("Embedded")
+                .field("inner", inner)
+                .field("start", start)
+                .field("len", len)
+                .finish(),
+        }
+    }
+}
+
+impl Source {
+    /// The volume set whose first volume is `first`, the next ones being the files with the
+    /// following numbers as extension
+    pub fn volumes(first: &Path) -> std::io::Result<Self> {
+        let (mut files, mut starts, mut len) = (Vec::new(), Vec::new(), 0);
+        let mut path = first.to_path_buf();
+        for number in 2.. {
+            let file = SharedFile::new(std::fs::File::open(&path)?);
+            starts.push(len);
+            len += file.metadata()?.len();
+            files.push(file);
+            path.set_extension(format!("{:03}", number));
+            if !path.is_file() {
+                break;
+            }
+        }
+        Ok(Self::Volumes { files, starts, len })
+    }
+
+    /// The archive appended to this source, if it ends with a trailer giving where it starts
+    pub fn embedded(self) -> std::io::Result<Self> {
+        let len = self.len()?;
+        let trailer_offset = match len.checked_sub(super::utils::TRAILER_LEN) {
+            Some(offset) => offset,
+            None => return Ok(self),
+        };
+        let mut trailer = [0x00; super::utils::TRAILER_LEN as usize];
+        self.read_exact_at(&mut trailer, trailer_offset)?;
+        let start = super::utils::slice_to_u64(&trailer[0..8]);
+        if trailer[8..] != super::utils::TRAILER_MAGIC || start > trailer_offset {
+            return Ok(self);
+        }
+        Ok(Self::Embedded {
+            inner: Box::new(self),
+            start,
+            len: trailer_offset - start,
+        })
+    }
+
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let slice = match self {
+            Self::File(file) => return file.read_at(buf, offset),
+            #[cfg(feature = "mmap")]
+            Self::Mmap(map) => map,
+            Self::Bytes(bytes) => (**bytes).as_ref(),
+            Self::Volumes { files, starts, len } => {
+                if offset >= *len {
+                    return Ok(0);
+                }
+                // Reads stop at the end of the volume, the next one continuing from there
+                let volume = starts.partition_point(|start| *start <= offset) - 1;
+                let end = starts.get(volume + 1).copied().unwrap_or(*len);
+                let left = end - offset;
+                let n = buf
+                    .len()
+                    .min(std::convert::TryFrom::try_from(left).unwrap_or(usize::MAX));
+                return files[volume].read_at(&mut buf[..n], offset - starts[volume]);
+            }
+            Self::Embedded { inner, start, len } => {
+                // Nothing past the archive is read, the trailer included
+                let left = len.saturating_sub(offset);
+                let n = buf
+                    .len()
+                    .min(std::convert::TryFrom::try_from(left).unwrap_or(usize::MAX));
+                if n == 0 {
+                    return Ok(0);
+                }
+                return inner.read_at(&mut buf[..n], start + offset);
+            }
+        };
+        let start = slice
+            .len()
+            .min(std::convert::TryFrom::try_from(offset).unwrap_or(usize::MAX));
+        let n = buf.len().min(slice.len() - start);
+        buf[..n].copy_from_slice(&slice[start..start + n]);
+        Ok(n)
+    }
+
+    /// The whole archive, if it is in memory
+    pub fn as_slice(&self) -> Option<&[u8]> {
+        match self {
+            Self::File(_) | Self::Volumes { .. } => None,
+            #[cfg(feature = "mmap")]
+            Self::Mmap(map) => Some(map),
+            Self::Bytes(bytes) => Some((**bytes).as_ref()),
+            Self::Embedded { inner, start, len } => inner.slice(*start, *len),
+        }
+    }
+
+    /// The `len` bytes at `offset`, if the archive is in memory and holds them
+    pub fn slice(&self, offset: u64, len: u64) -> Option<&[u8]> {
+        let start: usize = std::convert::TryFrom::try_from(offset).ok()?;
+        let len: usize = std::convert::TryFrom::try_from(len).ok()?;
+        self.as_slice()?.get(start..start.checked_add(len)?)
+    }
+
+    /// Fill `buf` with the bytes starting at `offset`
+    pub fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> std::io::Result<u64> {
+        match self {
+            Self::File(file) => Ok(file.metadata()?.len()),
+            #[cfg(feature = "mmap")]
+            Self::Mmap(map) => Ok(map.len() as u64),
+            Self::Bytes(bytes) => Ok((**bytes).as_ref().len() as u64),
+            Self::Volumes { len, .. } | Self::Embedded { len, .. } => Ok(*len),
+        }
+    }
+
+    /// A reader over the source, starting at `offset`
+    pub fn reader(&self, offset: u64) -> Reader<'_> {
+        Reader {
+            source: self,
+            pos: offset,
+        }
+    }
+}
+
+/// A file read at any offset from several threads at once
+///
+/// Unix and Windows have positional reads, other targets seek then read while holding a lock
+#[derive(Debug)]
+pub struct SharedFile {
+    #[cfg(any(unix, windows))]
+    file: std::fs::File,
+    #[cfg(not(any(unix, windows)))]
+    file: std::sync::Mutex<std::fs::File>,
+}
+
+impl SharedFile {
+    pub fn new(file: std::fs::File) -> Self {
+        #[cfg(not(any(unix, windows)))]
+        let file = std::sync::Mutex::new(file);
+        SharedFile { file }
+    }
+
+    pub fn metadata(&self) -> std::io::Result<std::fs::Metadata> {
+        #[cfg(any(unix, windows))]
+        return self.file.metadata();
+        #[cfg(not(any(unix, windows)))]
+        return self.lock().metadata();
+    }
+
+    /// Read the bytes at `offset`, without moving the file's cursor on Unix and Windows
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        #[cfg(unix)]
+        return std::os::unix::fs::FileExt::read_at(&self.file, buf, offset);
+        #[cfg(windows)]
+        return std::os::windows::fs::FileExt::seek_read(&self.file, buf, offset);
+        #[cfg(not(any(unix, windows)))]
+        {
+            let mut file = self.lock();
+            file.seek(SeekFrom::Start(offset))?;
+            file.read(buf)
+        }
+    }
+
+    /// The file, even if a thread panicked while holding it as the cursor is always moved first
+    #[cfg(not(any(unix, windows)))]
+    fn lock(&self) -> std::sync::MutexGuard<'_, std::fs::File> {
+        self.file
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+/// A cursor over a [Source], each reader having its own position
+#[derive(Debug)]
+pub struct Reader<'a> {
+    source: &'a Source,
+    pos: u64,
+}
+
+impl Read for Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.source.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Reader<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+            SeekFrom::End(n) => self.source.len()?.checked_add_signed(n),
+        };
+        self.pos = pos.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid argument")
+        })?;
+        Ok(self.pos)
+    }
+}
@@ -5,24 +5,58 @@ pub fn slice_to_u64(slice: &[u8]) -> u64 {
     }
     out
 }
-/**(flag,headersize,file_name)*/
-pub fn parse_header(slice: &[u8]) -> (bool, u64, String) {
-    let filename_length = slice[0] as u64 >> 1;
-    let filesize = slice_to_u64(&slice[1..9]);
-    let flag = (slice[0] & 1) == 1;
-    let filename = String::from_utf8(
-        slice[9..(9 + filename_length) as usize]
-            .iter()
-            .map(|x| *x)
-            .collect(),
-    )
-    .expect("A filename isn't valid UTF-8")
-    .to_owned();
-    (flag, filesize, filename)
+
+/// Registration flag: the file's data is a sparse map followed by its data extents
+pub const FLAG_SPARSE: u8 = 0b0000_0001;
+/// Registration flag (version 2): the size is followed by the absolute offset of the data, which
+/// isn't with the other childs' data
+pub const FLAG_OFFSET: u8 = 0b0000_0010;
+
+/// Length of the footer ending append-only archives: the offset and length of the root's
+/// registration, then [FOOTER_MAGIC]
+pub const FOOTER_LEN: u64 = 8 + 8 + 4;
+/// Last bytes of append-only archives
+pub const FOOTER_MAGIC: [u8; 4] = *b"KLUI";
+
+/// Length of the trailer ending a file an archive has been appended to: the offset where the
+/// archive starts, then [TRAILER_MAGIC]
+pub const TRAILER_LEN: u64 = 8 + 4;
+/// Last bytes of a file an archive has been appended to
+pub const TRAILER_MAGIC: [u8; 4] = *b"KLUE";
+
+/// Flags of the file registration starting at `slice[0]`, 0 if it is cut
+fn flags(slice: &[u8], version: u8) -> u8 {
+    match slice.get(1) {
+        Some(flags) if version >= 1 => *flags,
+        _ => 0,
+    }
 }
 
-pub fn split_in_place<T: Copy>(v: &mut Vec<T>, boundry: usize) -> Vec<T> {
-    let out = v[..boundry].iter().map(|x| *x).collect();
-    *v = v[boundry..].iter().map(|x| *x).collect();
-    out
+/// Length of the file registration starting at `slice[0]`
+pub fn header_len(slice: &[u8], version: u8) -> usize {
+    let offset = version >= 2 && flags(slice, version) & FLAG_OFFSET != 0;
+    (slice[0] >> 1) as usize + 8 + 1 + (version >= 1) as usize + 8 * offset as usize
+}
+
+/**(flag,flags,headersize,offset,file_name), fails if the name isn't valid UTF-8*/
+pub fn parse_header(
+    slice: &[u8],
+    version: u8,
+) -> Result<(bool, u8, u64, Option<u64>, String), std::string::FromUtf8Error> {
+    let filename_length = (slice[0] >> 1) as usize;
+    let flag = (slice[0] & 1) == 1;
+    let flags = flags(slice, version);
+    let slice = if version >= 1 {
+        &slice[2..]
+    } else {
+        &slice[1..]
+    };
+    let filesize = slice_to_u64(&slice[0..8]);
+    let (offset, slice) = if version >= 2 && flags & FLAG_OFFSET != 0 {
+        (Some(slice_to_u64(&slice[8..16])), &slice[16..])
+    } else {
+        (None, &slice[8..])
+    };
+    let filename = String::from_utf8(slice[..filename_length].to_vec())?;
+    Ok((flag, flags, filesize, offset, filename))
 }
@@ -0,0 +1,78 @@
+use super::utils;
+
+/// An entry of a central index, see [central_index]
+pub(crate) struct IndexEntry {
+    pub(crate) name: String,
+    pub(crate) is_file: bool,
+    pub(crate) is_sparse: bool,
+    /// Stored size of a file's data, ignored for directories
+    pub(crate) size: u64,
+    /// Absolute offset of a file's data, ignored for directories
+    pub(crate) offset: u64,
+    pub(crate) childs: Vec<IndexEntry>,
+}
+
+impl IndexEntry {
+    /// Length of its registration, with an offset
+    pub(crate) fn registration_len(&self) -> u64 {
+        2 + 8 + 8 + self.name.len() as u64
+    }
+
+    /// Length of the header of a directory, size included
+    fn header_len(&self) -> u64 {
+        8 + self
+            .childs
+            .iter()
+            .map(IndexEntry::registration_len)
+            .sum::<u64>()
+    }
+}
+
+/// The central index of the tree `root`, to be written at `offset`: the root's registration, then
+/// the header of every directory, breadth first. Every registration gives the offset of its data,
+/// a directory's data being its header
+pub(crate) fn central_index(root: &IndexEntry, offset: u64) -> Vec<u8> {
+    let mut index = Vec::new();
+    // Where the next directory's header goes
+    let mut next = offset + root.registration_len();
+    let mut dirs = std::collections::VecDeque::new();
+    index.extend_from_slice(&registration(root, &mut next, &mut dirs));
+    while let Some(dir) = dirs.pop_front() {
+        let h_size = dir.header_len() - 8;
+        index.extend_from_slice(&utils::u64_to_slice(h_size));
+        for child in &dir.childs {
+            index.extend_from_slice(&registration(child, &mut next, &mut dirs));
+        }
+    }
+    index
+}
+
+/// The registration of `entry`, a directory being given the header at `next` and queued
+fn registration<'a>(
+    entry: &'a IndexEntry,
+    next: &mut u64,
+    dirs: &mut std::collections::VecDeque<&'a IndexEntry>,
+) -> Box<[u8]> {
+    if entry.is_file {
+        return super::registration(
+            &entry.name,
+            true,
+            entry.is_sparse,
+            entry.size,
+            Some(entry.offset),
+        );
+    }
+    let (offset, size) = (*next, entry.header_len());
+    *next += size;
+    dirs.push_back(entry);
+    super::registration(&entry.name, false, false, size, Some(offset))
+}
+
+/// The footer ending an append-only archive, pointing to the root's registration
+pub(crate) fn footer(root_offset: u64, root_len: u64) -> Vec<u8> {
+    let mut footer = Vec::with_capacity(utils::FOOTER_LEN);
+    footer.extend_from_slice(&utils::u64_to_slice(root_offset));
+    footer.extend_from_slice(&utils::u64_to_slice(root_len));
+    footer.extend_from_slice(&utils::FOOTER_MAGIC);
+    footer
+}
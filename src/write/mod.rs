@@ -15,16 +15,36 @@
 
 /*
  * Archive:
- *      0x00 - 0x03: b"KLU "
+ *      0x00 - 0x03: b"KLU" + format version (0x01, 0x02 once edited in place, 0x03 append-only)
  *      0x04 - 0x0B: headersize (u64)
  *      0x0C - 0x13: file size  (u64)
  *      0x13 - 0x13 + headersize: File Registrations;
  *      0x14 + headersize - EOF : File Data
+ *  Append-only archive (version 3), registrations as in version 2:
+ *      0x00 - 0x03: b"KLU\x03"
+ *      then the files' data, then the central index: the root's registration followed by the
+ *      header of every directory, each registration giving the offset of its data
+ *      EOF - 0x14 - EOF - 0x0D: offset of the root's registration (u64)
+ *      EOF - 0x0C - EOF - 0x05: headersize (u64)
+ *      EOF - 0x04 - EOF - 0x01: b"KLUI"
+ *      Appending writes the new data then a new central index and footer, the last footer
+ *      pointing to the latest index
+ *  Archive appended to another file:
+ *      the other file, then the archive, then the trailer:
+ *      EOF - 0x0C - EOF - 0x05: offset of the archive (u64)
+ *      EOF - 0x04 - EOF - 0x01: b"KLUE"
+ *  Archive split into volumes:
+ *      `<name>.001`, `<name>.002`... holding the archive's bytes one after the other
+ *  Archive with several top-level entries:
+ *      the root is a directory with an empty Filename, the top-level entries being its childs
  *  File Registrations:
  *                                  0b_______*
  *      0x0: 7b => Filename length; 1b: dir flag (0=dir;1=file)
- *      0x1 - 0x08 => File Size
- *      0x09 + 0x09 + Filename length => Filename
+ *      0x1 => Flags (0b_______*: sparse; 0b______*_: data offset, version 2)
+ *      0x2 - 0x09 => File Size (size of the data stored in the archive)
+ *      With the data offset flag:
+ *          0x0A - 0x11 => Data offset (absolute, the data isn't with the other childs')
+ *      then Filename
  *  File:
  *      Is a dir:
  *          0x00 - 0x07: Headersize
@@ -32,15 +52,27 @@
  *          0x09 - 0x09 + dir size: files data
  *      Is a file:
  *          0x0 - 0x0 + filesize : raw bytes
+ *      Is a sparse file:
+ *          0x00 - 0x07: Apparent size
+ *          0x08 - 0x0F: Extent count
+ *          0x10 - 0x10 + 16 * count: Extents (offset (u64), length (u64))
+ *          then the extents' bytes, one after the other
  */
 use std::path::{PathBuf,Path};
 use std::io::prelude::*;
+use std::io::SeekFrom;
+mod index;
+mod pack;
 mod utils;
+mod volumes;
+pub(crate) use index::{central_index, footer, IndexEntry};
+pub use pack::{ErrorPolicy, PackOptions};
 #[derive(Debug)]
 pub struct Archive {
     headersize: u64,
     filesize: u64,
     file: File,
+    skipped: Vec<(PathBuf, WriteError)>,
 }
 
 pub type WriteResult<T> = Result<T,WriteError>;
@@ -48,14 +80,18 @@ pub type WriteResult<T> = Result<T,WriteError>;
 #[derive(Debug)]
 pub enum WriteError {
     IoError(std::io::Error),
-    InvalidInput(Filename)
+    InvalidInput(Filename),
+    /// A file's size changed between the moment it was scanned and the moment it was written
+    SourceChanged(String),
 }
 
 #[derive(Debug)]
 pub enum Filename {
     NotUTF8(String),
     TooLong(String),
-    Inexistant(String)
+    Inexistant(String),
+    /// Two top-level entries have the same name, see [Archive::from_paths]
+    Duplicate(String),
 }
 
 impl std::convert::From<std::io::Error> for WriteError {
@@ -63,6 +99,14 @@ impl std::convert::From<std::io::Error> for WriteError {
         Self::IoError(err)
     }
 }
+impl std::error::Error for WriteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::IoError(e) => Some(e),
+            _ => None
+        }
+    }
+}
 impl std::fmt::Display for WriteError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         write!(
@@ -70,44 +114,175 @@ impl std::fmt::Display for WriteError {
             "{}",
             match self {
                 Self::IoError(e) => e.to_string(),
-                Self::InvalidInput(e) => format!("{}",match e {
-                    Filename::NotUTF8(s) => s,
-                    Filename::TooLong(s) => s,
-                    Filename::Inexistant(s) => s,
-                })
+                Self::InvalidInput(e) => match e {
+                    Filename::NotUTF8(s) => s.clone(),
+                    Filename::TooLong(s) => s.clone(),
+                    Filename::Inexistant(s) => s.clone(),
+                    Filename::Duplicate(s) => s.clone(),
+                },
+                Self::SourceChanged(s) => s.clone(),
             }
         )
     }
 }
 
 impl Archive {
-    /// The 4 bytes at the start of any archive
-    const ID: [u8; 4] = *b"KLU\x00";
+    /// The 4 bytes at the start of any archive, the last one being the format version
+    pub(crate) const ID: [u8; 4] = *b"KLU\x01";
+    /// The ID of append-only archives, see [Archive::write_indexed_to]
+    pub(crate) const INDEXED_ID: [u8; 4] = *b"KLU\x03";
     /// Create an archive from the path
     pub fn from_path<P:AsRef<Path>>(path: P) -> WriteResult<Self> {
-        let file = File::from_path(path)?;
+        Self::from_path_with(path, &PackOptions::default())
+    }
+    /// Create an archive from the path, only packing the entries accepted by `options`
+    /// The entries that couldn't be read are handled following the [options' error
+    /// policy](PackOptions::on_error), see [Archive::skipped]
+    pub fn from_path_with<P:AsRef<Path>>(path: P, options: &PackOptions) -> WriteResult<Self> {
+        let mut skipped = Vec::new();
+        let file = File::from_path_report(path, options, &mut skipped)?;
+        let mut archive = Self::from_file(file);
+        archive.skipped = skipped;
+        Ok(archive)
+    }
+    /// Create an archive with several top-level entries, one per path, instead of a single root
+    pub fn from_paths<I, P>(paths: I) -> WriteResult<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        Self::from_paths_with(paths, &PackOptions::default())
+    }
+    /// Same as [Archive::from_paths], packing the entries as [Archive::from_path_with] does
+    ///
+    /// The top-level entries keep the order of `paths`, unless the options ask for a
+    /// reproducible archive
+    pub fn from_paths_with<I, P>(paths: I, options: &PackOptions) -> WriteResult<Self>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        let mut skipped = Vec::new();
+        let mut roots: Vec<File> = Vec::new();
+        for path in paths {
+            let file = File::from_path_report(path, options, &mut skipped)?;
+            if roots.iter().any(|root| root.filename == file.filename) {
+                return Err(WriteError::InvalidInput(Filename::Duplicate(format!(
+                    "Two top-level entries are named `{}`",
+                    file.filename
+                ))));
+            }
+            roots.push(file);
+        }
+        if options.reproducible {
+            roots.sort_by(|a, b| a.filename.cmp(&b.filename));
+        }
+        let mut archive = Self::from_file(File::directory("", roots));
+        archive.skipped = skipped;
+        Ok(archive)
+    }
+    /// Create an archive whose root is `file`
+    pub(crate) fn from_file(file: File) -> Self {
         let filesize =  Self::ID.len() as u64 + 
                         8 /* headersize */ + 
                         8 /* filesize */ + 
                         file.header_len() as u64 + file.filesize;
-        Ok(Archive {
+        Archive {
             headersize: file.header_len() as u64,
-            filesize: filesize,
-            file: file,
-        })
+            filesize,
+            file,
+            skipped: Vec::new(),
+        }
+    }
+    /// The entries left out of the archive because they couldn't be read, with the error
+    /// encountered. Always empty unless the error policy skips errors
+    pub fn skipped(&self) -> &[(PathBuf, WriteError)] {
+        &self.skipped
+    }
+    /// Paths of every file and directory packed in the archive, on disk
+    pub fn sources(&self) -> Vec<&Path> {
+        let mut sources = Vec::new();
+        self.file.sources(&mut sources);
+        sources
     }
     ///Write archive to file at given path. Will create a new file or truncate it if allready
-    ///existing
+    ///existing. The file is removed if writing fails, a source having changed for instance
     pub fn write_to_path<P:AsRef<Path>>(&self, path : P) -> WriteResult<()> {
-        let out_file = std::fs::File::create(path)?;
-        let mut buffer = std::io::BufWriter::new(out_file);
-        buffer.write(&Self::ID)?;
-        buffer.write(&utils::u64_to_slice(self.headersize))?;
-        buffer.write(&utils::u64_to_slice(self.filesize))?;
-        buffer.write(&self.file.header())?;
+        let result = self.write_to(std::fs::File::create(&path)?);
+        if result.is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        result
+    }
+    /// Write archive to `out`, which is buffered
+    pub fn write_to<W: Write>(&self, out: W) -> WriteResult<()> {
+        let mut buffer = std::io::BufWriter::new(out);
+        buffer.write_all(&Self::ID)?;
+        buffer.write_all(&utils::u64_to_slice(self.headersize))?;
+        buffer.write_all(&utils::u64_to_slice(self.filesize))?;
+        buffer.write_all(&self.file.header())?;
         self.file.write_to_buf(&mut buffer)?;
+        buffer.flush()?;
         Ok(())
     } 
+    /// Write archive split into volumes of at most `max_volume_size` bytes: `<path>.001`,
+    /// `<path>.002`... Returns the paths of the volumes, which are read back by giving the first
+    /// one to [crate::read::Archive::from_volumes]. The volumes are removed if writing fails
+    pub fn write_volumes_to_path<P:AsRef<Path>>(&self, path: P, max_volume_size: u64) -> WriteResult<Vec<PathBuf>> {
+        if max_volume_size == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "volumes can't be empty").into());
+        }
+        let mut volumes = volumes::Volumes::new(path.as_ref(), max_volume_size);
+        if let Err(error) = self.write_to(&mut volumes) {
+            for path in &volumes.paths {
+                let _ = std::fs::remove_file(path);
+            }
+            return Err(error);
+        }
+        volumes.remove_stale()?;
+        Ok(volumes.paths)
+    }
+    /// Append the archive to the file at `path`, an executable for instance, followed by a
+    /// trailer giving where it starts. [crate::read::Archive] then finds it when reading that file.
+    /// The file is truncated back to its previous length if writing fails
+    pub fn append_to_path<P:AsRef<Path>>(&self, path: P) -> WriteResult<()> {
+        let mut file = std::fs::OpenOptions::new().append(true).open(path)?;
+        let start = file.metadata()?.len();
+        let mut append = || -> WriteResult<()> {
+            self.write_to(&mut file)?;
+            file.write_all(&utils::u64_to_slice(start))?;
+            file.write_all(&utils::TRAILER_MAGIC)?;
+            Ok(())
+        };
+        let result = append();
+        if result.is_err() {
+            let _ = file.set_len(start);
+        }
+        result
+    }
+    ///Write archive to file at given path with the append-only layout, see
+    ///[Archive::write_indexed_to]. The file is removed if writing fails
+    pub fn write_indexed_to_path<P:AsRef<Path>>(&self, path : P) -> WriteResult<()> {
+        let result = self.write_indexed_to(std::fs::File::create(&path)?);
+        if result.is_err() {
+            let _ = std::fs::remove_file(path);
+        }
+        result
+    }
+    /// Write archive to `out` with the append-only layout: the files' data comes first, then a
+    /// central index of every registration and directory header, then a footer pointing to it.
+    /// Entries can then be added to the archive with [crate::edit::Editor] without moving any
+    /// data, each edit appending a new index
+    pub fn write_indexed_to<W: Write>(&self, out: W) -> WriteResult<()> {
+        let mut buffer = std::io::BufWriter::new(out);
+        buffer.write_all(&Self::INDEXED_ID)?;
+        let mut offset = Self::INDEXED_ID.len() as u64;
+        let root = self.file.write_data(&mut buffer, &mut offset)?;
+        buffer.write_all(&central_index(&root, offset))?;
+        buffer.write_all(&footer(offset, root.registration_len()))?;
+        buffer.flush()?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -118,36 +293,69 @@ pub struct File {
     filename: String,
     path: PathBuf,
     childs: Vec<File>,
+    /// Data extents of a sparse file, [None] for a regular file
+    extents: Option<Vec<(u64, u64)>>,
+    /// Size of the file on disk, holes included
+    apparent_size: u64,
+    /// Content read while scanning, for files under [PackOptions::snapshot_below]
+    snapshot: Option<Box<[u8]>>,
 }
 
 impl File {
     ///Get the file header
     pub fn header(&self) -> Box<[u8]> {
-        let mut header = vec![0x00_u8; 1];
-        header[0x00] = (self.filename.len() << 1) as u8 | self.is_file as u8;
-        header = [&*header, &*utils::u64_to_slice(self.filesize)].concat();
-        header = [&*header, self.filename.as_bytes()].concat();
-        header.into_boxed_slice()
+        registration(&self.filename, self.is_file, self.extents.is_some(), self.filesize, None)
+    }
+    pub(crate) fn is_file(&self) -> bool {
+        self.is_file
+    }
+    /// True if the data is a sparse map followed by the extents' data
+    pub(crate) fn is_sparse(&self) -> bool {
+        self.extents.is_some()
+    }
+    /// Size of the data, once written
+    pub(crate) fn filesize(&self) -> u64 {
+        self.filesize
     }
     /// Return the file's header length
     pub fn header_len(&self) -> usize {
-        return 1 /*filename length + dir bit*/ + 8 /*filesize (u64)*/ + self.filename.len();
+        1 /*filename length + dir bit*/ + 1 /*flags*/ + 8 /*filesize (u64)*/ + self.filename.len()
     }
     /// Write file to given buffer, needs to be a mutable reference because it 
     /// will be given to file's children an so on;
     pub fn write_to_buf<W:Write>(&self, buffer: &mut std::io::BufWriter<W>) -> WriteResult<()>{
-        if self.is_file {
+        if let Some(extents) = &self.extents {
+            buffer.write_all(&utils::u64_to_slice(self.apparent_size))?;
+            buffer.write_all(&utils::u64_to_slice(extents.len() as u64))?;
+            for (offset, length) in extents {
+                buffer.write_all(&utils::u64_to_slice(*offset))?;
+                buffer.write_all(&utils::u64_to_slice(*length))?;
+            }
             let mut reader = std::io::BufReader::new(std::fs::File::open(&self.path)?);
-            std::io::copy(&mut reader,buffer)?;
-            buffer.flush()?;
+            for (offset, length) in extents {
+                reader.seek(SeekFrom::Start(*offset))?;
+                self.copy_exact(&mut reader, buffer, *length)?;
+            }
+            if reader.get_ref().metadata()?.len() != self.apparent_size {
+                return Err(self.changed());
+            }
+        } else if let Some(snapshot) = &self.snapshot {
+            buffer.write_all(snapshot)?;
+        } else if self.is_file {
+            let mut reader = std::io::BufReader::new(std::fs::File::open(&self.path)?);
+            self.copy_exact(&mut reader, buffer, self.filesize)?;
+            // Every offset after this file would be off if it has grown
+            if reader.read(&mut [0])? != 0 {
+                return Err(self.changed());
+            }
         } else {
             let mut headersize = 0;
             for c in &self.childs {
                 headersize += c.header_len() as u64;
             }
-            buffer.write(&utils::u64_to_slice(headersize))?;
+            buffer.write_all(&utils::u64_to_slice(headersize))?;
             for c in &self.childs {   
-                buffer.write(&c.header())?;
+                buffer.write_all(&c.header())?;
             }
             for c in &self.childs {
                 c.write_to_buf(buffer)?;
@@ -156,15 +364,191 @@ impl File {
         Ok(())
     }
 
+    /// Write the data of every file, without the directories' headers, `offset` being where
+    /// the next one goes. Returns the entry with the offsets of the data, for the central index
+    fn write_data<W:Write>(&self, buffer: &mut std::io::BufWriter<W>, offset: &mut u64) -> WriteResult<IndexEntry> {
+        let mut entry = IndexEntry {
+            name: self.filename.clone(),
+            is_file: self.is_file,
+            is_sparse: self.extents.is_some(),
+            size: self.filesize,
+            offset: *offset,
+            childs: Vec::new(),
+        };
+        if self.is_file {
+            self.write_to_buf(buffer)?;
+            *offset += self.filesize;
+        } else {
+            for c in &self.childs {
+                entry.childs.push(c.write_data(buffer, offset)?);
+            }
+        }
+        Ok(entry)
+    }
+
+    fn sources<'a>(&'a self, v: &mut Vec<&'a Path>) {
+        // Entries that aren't read from the disk have no path
+        if !self.path.as_os_str().is_empty() {
+            v.push(&self.path);
+        }
+        for c in &self.childs {
+            c.sources(v);
+        }
+    }
+
+    /// Copy exactly `length` bytes from `reader`, failing if the file is shorter
+    fn copy_exact<R: Read, W: Write>(&self, reader: &mut R, buffer: &mut W, length: u64) -> WriteResult<()> {
+        if std::io::copy(&mut reader.take(length), buffer)? != length {
+            return Err(self.changed());
+        }
+        Ok(())
+    }
+
+    fn changed(&self) -> WriteError {
+        WriteError::SourceChanged(format!(
+            "File `{}` changed size since it was scanned, expected {} bytes",
+            self.path.display(),
+            self.apparent_size
+        ))
+    }
+
+    /// A file holding `data`, which isn't read from the disk
+    pub(crate) fn from_memory(filename: &str, data: Vec<u8>) -> Self {
+        File {
+            filesize: data.len() as u64,
+            is_file: true,
+            filename: filename.to_owned(),
+            path: PathBuf::new(),
+            childs: Vec::new(),
+            extents: None,
+            apparent_size: data.len() as u64,
+            snapshot: Some(data.into_boxed_slice()),
+        }
+    }
+
+    /// A directory holding `childs`, which isn't read from the disk
+    pub(crate) fn directory(filename: &str, childs: Vec<File>) -> Self {
+        let filesize = 8 + childs.iter().map(|c| c.header_len() as u64 + c.filesize).sum::<u64>();
+        File {
+            filesize,
+            is_file: false,
+            filename: filename.to_owned(),
+            path: PathBuf::new(),
+            childs,
+            extents: None,
+            apparent_size: filesize,
+            snapshot: None,
+        }
+    }
+
     /// Create a [File] from a [PathBuf], will populate childs if needed
     pub fn from_path<P:AsRef<Path>>(path: P) -> WriteResult<Self> {
-        let path = path.as_ref()
-            .canonicalize()?;
+        Self::from_path_with(path, &PackOptions::default())
+    }
+
+    /// Create a [File] from a [PathBuf], populating childs with the entries accepted by `options`
+    pub fn from_path_with<P:AsRef<Path>>(path: P, options: &PackOptions) -> WriteResult<Self> {
+        Self::from_path_report(path, options, &mut Vec::new())
+    }
+
+    /// Same as [File::from_path_with], pushing the entries skipped by the
+    /// [options' error policy](PackOptions::on_error) onto `skipped`
+    pub fn from_path_report<P:AsRef<Path>>(
+        path: P,
+        options: &PackOptions,
+        skipped: &mut Vec<(PathBuf, WriteError)>,
+    ) -> WriteResult<Self> {
+        Ok(Self::walk(path.as_ref(), options, "", 0, &mut Vec::new(), skipped)?
+            .expect("The root's errors are never skipped"))
+    }
+
+    /// `relative` is the path from the packed root, `ignores` the rules of the ignore files found
+    /// in the parent directories. Returns [None] if the entry has been skipped
+    fn walk(
+        path: &Path,
+        options: &PackOptions,
+        relative: &str,
+        depth: usize,
+        ignores: &mut Vec<pack::IgnoreRules>,
+        skipped: &mut Vec<(PathBuf, WriteError)>,
+    ) -> WriteResult<Option<Self>> {
+        let scanned = Self::scan(path, options).and_then(|file| {
+            if file.is_file {
+                return Ok((file, Vec::new(), None));
+            }
+            let rules = options.ignore_rules(&file.path, relative)?;
+            let entries = file.path.read_dir()?;
+            Ok((file, rules, Some(entries)))
+        });
+        let (mut file, rules, entries) = match scanned {
+            Ok(scanned) => scanned,
+            // Errors of the root itself are always returned
+            Err(err) if depth > 0 => return options.skip(path, err, skipped).map(|_| None),
+            Err(err) => return Err(pack::with_path(path, err)),
+        };
+        if let Some(entries) = entries {
+            let pushed = rules.len();
+            ignores.extend(rules);
+            for entry in entries {
+                let child = match entry {
+                    Ok(child) => child,
+                    Err(err) => {
+                        options.skip(&file.path, err.into(), skipped)?;
+                        continue;
+                    }
+                };
+                let name = child.file_name().to_string_lossy().into_owned();
+                let child_relative = if relative.is_empty() {
+                    name
+                } else {
+                    format!("{}/{}", relative, name)
+                };
+                let is_dir = child.path().is_dir();
+                if !options.accepts(&child, &child_relative, depth + 1, is_dir, ignores) {
+                    continue;
+                }
+                let child_file = Self::walk(&child.path(), options, &child_relative, depth + 1, ignores, skipped)?;
+                if let Some(child_file) = child_file {
+                    if options.prunes_empty_dirs() && !child_file.is_file && child_file.childs.is_empty() {
+                        continue;
+                    }
+                    file.filesize += child_file.header_len() as u64 + child_file.filesize;
+                    file.childs.push(child_file);
+                }
+            }
+            ignores.truncate(ignores.len() - pushed);
+            if options.reproducible {
+                file.childs.sort_by(|a, b| a.filename.cmp(&b.filename));
+            }
+        }
+        Ok(Some(file))
+    }
+
+    /// Read the entry at `path` itself, without its childs
+    fn scan(path: &Path, options: &PackOptions) -> WriteResult<Self> {
+        let path = path.canonicalize()?;
         let md = path
             .metadata()?;
         let mut filesize = if md.is_file() { md.len() } else { 8 };
+        let mut apparent_size = md.len();
+        let mut extents = None;
+        let mut snapshot = None;
+        if md.is_file() && md.len() < options.snapshot_below {
+            let data = std::fs::read(&path)?.into_boxed_slice();
+            filesize = data.len() as u64;
+            apparent_size = filesize;
+            snapshot = Some(data);
+        } else if md.is_file() && !options.reproducible {
+            if let Some(e) = utils::data_extents(&std::fs::File::open(&path)?, md.len())? {
+                let data_len: u64 = e.iter().map(|(_, length)| length).sum();
+                if data_len < md.len() {
+                    filesize = 8 /*apparent size*/ + 8 /*extent count*/ + 16 * e.len() as u64 + data_len;
+                    extents = Some(e);
+                }
+            }
+        }
         if let Some(fname) = path.file_name() {
-            if None == fname.to_str() {
+            if fname.to_str().is_none() {
                 return Err(WriteError::InvalidInput(Filename::NotUTF8(
                             format!("Filename `{}` isn't valid UTF-8", path.display()))));
             }
@@ -177,22 +561,31 @@ impl File {
             return Err(WriteError::InvalidInput(Filename::Inexistant(
                         format!("Filename `{}` doesn't exist", path.display()))));
         }
-        let mut childs = Vec::new();
-        if md.is_dir() {
-            for dir in path.read_dir()? {
-                if let Ok(child) = dir {
-                    let child_file = Self::from_path(child.path())?;
-                    filesize += child_file.header_len() as u64 + child_file.filesize;
-                    childs.push(child_file);
-                }
-            }
-        }
         Ok(File {
-            filesize: filesize,
+            filesize,
             is_file: md.is_file(),
             filename: path.file_name().unwrap().to_str().unwrap().to_owned(),
-            path: path,
-            childs: childs,
+            path,
+            childs: Vec::new(),
+            extents,
+            apparent_size,
+            snapshot,
         })
     }
 }
+
+/// Encode a file registration, with the offset of its data if it isn't with its siblings'
+pub(crate) fn registration(filename: &str, is_file: bool, is_sparse: bool, filesize: u64, offset: Option<u64>) -> Box<[u8]> {
+    let mut header = vec![0x00_u8; 2];
+    header[0x00] = (filename.len() << 1) as u8 | is_file as u8;
+    if is_sparse {
+        header[0x01] |= utils::FLAG_SPARSE;
+    }
+    header = [&*header, &*utils::u64_to_slice(filesize)].concat();
+    if let Some(offset) = offset {
+        header[0x01] |= utils::FLAG_OFFSET;
+        header = [&*header, &*utils::u64_to_slice(offset)].concat();
+    }
+    header = [&*header, filename.as_bytes()].concat();
+    header.into_boxed_slice()
+}
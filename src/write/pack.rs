@@ -0,0 +1,545 @@
+use super::WriteError;
+use std::path::{Path, PathBuf};
+
+/// Callback deciding if a [std::fs::DirEntry] gets packed
+type EntryFilter = Box<dyn Fn(&std::fs::DirEntry) -> bool>;
+
+/// Callback of [ErrorPolicy::Callback]
+type ErrorCallback = Box<dyn Fn(&Path, &WriteError) -> bool>;
+
+/// What to do with an entry that can't be read while packing
+pub enum ErrorPolicy {
+    /// Stop packing and return the error, the default
+    FailFast,
+    /// Leave the entry out of the archive and report it in [super::Archive::skipped]
+    Skip,
+    /// Ask the callback, which returns true to skip the entry and false to fail
+    Callback(ErrorCallback),
+}
+
+impl std::fmt::Debug for ErrorPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::FailFast => write!(f, "FailFast"),
+            Self::Skip => write!(f, "Skip"),
+            Self::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+/// Options controlling which entries [super::Archive::from_path_with] packs
+///
+/// Patterns follow the `.gitignore` syntax: `*` and `?` don't cross a `/`, `**` does, `[a-z]`
+/// matches a class. A pattern without a `/` matches an entry's name at any depth, otherwise it is
+/// matched against the path relative to the packed root. A trailing `/` only matches directories.
+///
+/// The root given to `from_path_with` is always packed, the options only apply to its content.
+pub struct PackOptions {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    ignore_files: bool,
+    max_depth: Option<usize>,
+    hidden: bool,
+    filter: Option<EntryFilter>,
+    pub(crate) reproducible: bool,
+    on_error: ErrorPolicy,
+    pub(crate) snapshot_below: u64,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        PackOptions {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            ignore_files: false,
+            max_depth: None,
+            hidden: true,
+            filter: None,
+            reproducible: false,
+            on_error: ErrorPolicy::FailFast,
+            snapshot_below: 0,
+        }
+    }
+}
+
+impl std::fmt::Debug for PackOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("PackOptions")
+            .field("include", &self.include)
+            .field("exclude", &self.exclude)
+            .field("ignore_files", &self.ignore_files)
+            .field("max_depth", &self.max_depth)
+            .field("hidden", &self.hidden)
+            .field("filter", &self.filter.as_ref().map(|_| ".."))
+            .field("reproducible", &self.reproducible)
+            .field("on_error", &self.on_error)
+            .field("snapshot_below", &self.snapshot_below)
+            .finish()
+    }
+}
+
+impl PackOptions {
+    /// Options packing everything, the same as [super::Archive::from_path]
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Only pack files matching at least one include pattern. Directories are still walked, and
+    /// left out when no file inside them is included
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.extend(Pattern::parse(pattern));
+        self
+    }
+    /// Skip files and directories matching the pattern
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.extend(Pattern::parse(pattern));
+        self
+    }
+    /// Honor the `.gitignore` and `.kluignore` files found while walking the tree.
+    /// Their rules apply to the directory holding them and everything below it
+    pub fn ignore_files(mut self, enabled: bool) -> Self {
+        self.ignore_files = enabled;
+        self
+    }
+    /// Skip entries nested deeper than `depth`, the root's children being at depth 1
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+    /// Pack entries whose name starts with a `.` (the default)
+    pub fn hidden(mut self, enabled: bool) -> Self {
+        self.hidden = enabled;
+        self
+    }
+    /// Only pack the entries for which `filter` returns true, called after every other option
+    pub fn filter<F: Fn(&std::fs::DirEntry) -> bool + 'static>(mut self, filter: F) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+    /// Make the output only depend on the packed content: entries are sorted by their name's
+    /// bytes instead of following `read_dir`, and files are never stored as sparse as holes depend
+    /// on the filesystem. The format doesn't record timestamps, ownership or permissions, so
+    /// packing the same tree on two machines gives byte-identical archives
+    pub fn reproducible(mut self, enabled: bool) -> Self {
+        self.reproducible = enabled;
+        self
+    }
+    /// Choose what happens to the entries that can't be read, the root is never skipped
+    pub fn on_error(mut self, policy: ErrorPolicy) -> Self {
+        self.on_error = policy;
+        self
+    }
+
+    /// Read the files smaller than `size` bytes in memory while scanning the tree, so they can't
+    /// change before being written. Other files are checked to still have their scanned size when
+    /// written, failing with [WriteError::SourceChanged] otherwise
+    pub fn snapshot_below(mut self, size: u64) -> Self {
+        self.snapshot_below = size;
+        self
+    }
+
+    /// Apply the error policy to `err`, raised by the entry at `path`
+    pub(crate) fn skip(
+        &self,
+        path: &Path,
+        err: WriteError,
+        skipped: &mut Vec<(PathBuf, WriteError)>,
+    ) -> Result<(), WriteError> {
+        let skip = match &self.on_error {
+            ErrorPolicy::FailFast => false,
+            ErrorPolicy::Skip => true,
+            ErrorPolicy::Callback(callback) => callback(path, &err),
+        };
+        if !skip {
+            return Err(with_path(path, err));
+        }
+        skipped.push((path.to_path_buf(), err));
+        Ok(())
+    }
+
+    /// Returns true if the directories left empty by the include patterns are dropped
+    pub(crate) fn prunes_empty_dirs(&self) -> bool {
+        !self.include.is_empty()
+    }
+
+    /// Returns true if `entry`, at `relative` from the packed root, should be packed
+    pub(crate) fn accepts(
+        &self,
+        entry: &std::fs::DirEntry,
+        relative: &str,
+        depth: usize,
+        is_dir: bool,
+        ignores: &[IgnoreRules],
+    ) -> bool {
+        if let Some(max) = self.max_depth {
+            if depth > max {
+                return false;
+            }
+        }
+        if !self.hidden && entry.file_name().to_string_lossy().starts_with('.') {
+            return false;
+        }
+        if self.exclude.iter().any(|p| p.matches(relative, is_dir)) {
+            return false;
+        }
+        if !is_dir
+            && !self.include.is_empty()
+            && !self.include.iter().any(|p| p.matches(relative, is_dir))
+        {
+            return false;
+        }
+        // The deepest ignore file with a matching pattern decides
+        if let Some(ignored) = ignores
+            .iter()
+            .rev()
+            .find_map(|rules| rules.decide(relative, is_dir))
+        {
+            if ignored {
+                return false;
+            }
+        }
+        match &self.filter {
+            Some(filter) => filter(entry),
+            None => true,
+        }
+    }
+
+    /// Rules of the ignore files inside `dir`, found at `relative` from the packed root
+    pub(crate) fn ignore_rules(
+        &self,
+        dir: &Path,
+        relative: &str,
+    ) -> std::io::Result<Vec<IgnoreRules>> {
+        let mut rules = Vec::new();
+        if !self.ignore_files {
+            return Ok(rules);
+        }
+        for name in &[".gitignore", ".kluignore"] {
+            let path = dir.join(name);
+            if path.is_file() {
+                let patterns = std::fs::read_to_string(path)?
+                    .lines()
+                    .filter_map(Pattern::parse)
+                    .collect();
+                rules.push(IgnoreRules {
+                    base: relative.to_owned(),
+                    patterns,
+                });
+            }
+        }
+        Ok(rules)
+    }
+}
+
+/// Name the entry at `path` in an IO error, which only gives its cause. The other errors already
+/// name it
+pub(crate) fn with_path(path: &Path, err: WriteError) -> WriteError {
+    match err {
+        WriteError::IoError(e) => WriteError::IoError(std::io::Error::new(
+            e.kind(),
+            format!("{}: {}", path.display(), e),
+        )),
+        err => err,
+    }
+}
+
+/// The patterns of one ignore file
+#[derive(Debug)]
+pub(crate) struct IgnoreRules {
+    /// Directory holding the ignore file, relative to the packed root
+    base: String,
+    patterns: Vec<Pattern>,
+}
+
+impl IgnoreRules {
+    /// Returns `Some(true)` if `relative` is ignored, `Some(false)` if it is explicitly
+    /// re-included by a `!pattern`, [None] if no pattern matches
+    fn decide(&self, relative: &str, is_dir: bool) -> Option<bool> {
+        let relative = if self.base.is_empty() {
+            relative
+        } else {
+            match relative.strip_prefix(&self.base) {
+                Some(r) if r.starts_with('/') => &r[1..],
+                _ => return None,
+            }
+        };
+        // The last matching pattern wins
+        self.patterns
+            .iter()
+            .rev()
+            .find(|p| p.matches(relative, is_dir))
+            .map(|p| !p.negated)
+    }
+}
+
+#[derive(Debug)]
+struct Pattern {
+    glob: String,
+    negated: bool,
+    dir_only: bool,
+    anchored: bool,
+}
+
+impl Pattern {
+    /// Parse a `.gitignore` line, returns [None] for blank lines and comments
+    fn parse(line: &str) -> Option<Self> {
+        let mut line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        let negated = line.starts_with('!');
+        if negated {
+            line = &line[1..];
+        }
+        let dir_only = line.ends_with('/');
+        let line = line.trim_end_matches('/');
+        let anchored = line.contains('/');
+        let line = line.trim_start_matches('/');
+        if line.is_empty() {
+            return None;
+        }
+        Some(Pattern {
+            glob: line.to_owned(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, relative: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let text = if self.anchored {
+            relative
+        } else {
+            relative.rsplit('/').next().unwrap_or(relative)
+        };
+        glob_match(self.glob.as_bytes(), text.as_bytes())
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.first() {
+        None => text.is_empty(),
+        Some(b'*') if pattern.get(1) == Some(&b'*') => {
+            let rest = &pattern[2..];
+            // `**/` also matches no directory at all
+            if rest.first() == Some(&b'/') && glob_match(&rest[1..], text) {
+                return true;
+            }
+            (0..=text.len()).any(|i| glob_match(rest, &text[i..]))
+        }
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if text.get(i) == Some(&b'/') {
+                    break;
+                }
+            }
+            false
+        }
+        Some(b'?') => match text.first() {
+            Some(c) if *c != b'/' => glob_match(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'[') => match (text.first(), class_end(pattern)) {
+            (Some(c), Some(end)) if *c != b'/' => {
+                class_match(&pattern[1..end], *c) && glob_match(&pattern[end + 1..], &text[1..])
+            }
+            (Some(c), None) => *c == b'[' && glob_match(&pattern[1..], &text[1..]),
+            _ => false,
+        },
+        Some(b'\\') if pattern.len() > 1 => {
+            text.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &text[1..])
+        }
+        Some(p) => text.first() == Some(p) && glob_match(&pattern[1..], &text[1..]),
+    }
+}
+
+/// Index of the `]` closing the class opened at `pattern[0]`
+fn class_end(pattern: &[u8]) -> Option<usize> {
+    let start = if pattern.get(1) == Some(&b'!') { 3 } else { 2 };
+    (start..pattern.len()).find(|i| pattern[*i] == b']')
+}
+
+fn class_match(class: &[u8], c: u8) -> bool {
+    let (negated, class) = match class.first() {
+        Some(b'!') => (true, &class[1..]),
+        _ => (false, class),
+    };
+    let mut i = 0;
+    let mut found = false;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            found |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(base: &str, lines: &[&str]) -> IgnoreRules {
+        IgnoreRules {
+            base: base.to_owned(),
+            patterns: lines.iter().filter_map(|line| Pattern::parse(line)).collect(),
+        }
+    }
+
+    fn matches(pattern: &str, relative: &str, is_dir: bool) -> bool {
+        Pattern::parse(pattern).unwrap().matches(relative, is_dir)
+    }
+
+    #[test]
+    fn stars() {
+        assert!(glob_match(b"*.txt", b"a.txt"));
+        assert!(glob_match(b"*.txt", b".txt"));
+        assert!(!glob_match(b"*.txt", b"a/b.txt"));
+        assert!(glob_match(b"a?c", b"abc"));
+        assert!(!glob_match(b"a?c", b"a/c"));
+        assert!(glob_match(b"a/**", b"a/b/c"));
+        assert!(glob_match(b"**/c", b"a/b/c"));
+        // `**/` also matches no directory at all
+        assert!(glob_match(b"**/c", b"c"));
+        assert!(glob_match(b"a/**/c", b"a/c"));
+        assert!(glob_match(b"a/**/c", b"a/b/b/c"));
+        assert!(!glob_match(b"a/**/c", b"b/c"));
+        assert!(glob_match(b"\\*", b"*"));
+        assert!(!glob_match(b"\\*", b"a"));
+    }
+
+    #[test]
+    fn classes() {
+        assert!(class_match(b"a-c", b'b'));
+        assert!(!class_match(b"a-c", b'd'));
+        assert!(class_match(b"xa-c", b'x'));
+        assert!(class_match(b"!a-c", b'd'));
+        assert!(!class_match(b"!a-c", b'a'));
+        assert!(class_match(b"-a", b'-'));
+        assert!(glob_match(b"[0-9][0-9].log", b"42.log"));
+        assert!(!glob_match(b"[0-9].log", b"a.log"));
+        assert!(glob_match(b"[!.]*", b"file"));
+        assert!(!glob_match(b"[!.]*", b".hidden"));
+        // A `]` right after the opening `[` is part of the class
+        assert!(glob_match(b"[]]", b"]"));
+        // An unclosed class matches a literal `[`
+        assert!(glob_match(b"[ab", b"[ab"));
+        assert!(!glob_match(b"[a]", b"/"));
+    }
+
+    #[test]
+    fn anchoring() {
+        // Without a `/`, the name matches at any depth
+        assert!(matches("b.txt", "a/b.txt", false));
+        assert!(matches("*.txt", "a/b/c.txt", false));
+        // With one, the whole path from the root must match
+        assert!(matches("/b.txt", "b.txt", false));
+        assert!(!matches("/b.txt", "a/b.txt", false));
+        assert!(matches("a/*.txt", "a/b.txt", false));
+        assert!(!matches("a/*.txt", "c/a/b.txt", false));
+        assert!(matches("**/a/*.txt", "c/a/b.txt", false));
+    }
+
+    #[test]
+    fn directories_only() {
+        assert!(matches("build/", "build", true));
+        assert!(matches("build/", "src/build", true));
+        assert!(!matches("build/", "build", false));
+        assert!(matches("build", "build", false));
+    }
+
+    #[test]
+    fn parsing() {
+        assert!(Pattern::parse("").is_none());
+        assert!(Pattern::parse("   ").is_none());
+        assert!(Pattern::parse("# comment").is_none());
+        assert!(Pattern::parse("/").is_none());
+        let pattern = Pattern::parse("!/logs/ ").unwrap();
+        assert_eq!(pattern.glob, "logs");
+        assert!(pattern.negated && pattern.dir_only && pattern.anchored);
+    }
+
+    #[test]
+    fn negation() {
+        let ignore = rules("", &["*.log", "!keep.log"]);
+        assert_eq!(ignore.decide("a.log", false), Some(true));
+        assert_eq!(ignore.decide("d/keep.log", false), Some(false));
+        assert_eq!(ignore.decide("a.txt", false), None);
+        // The last matching pattern wins
+        let reversed = rules("", &["!keep.log", "*.log"]);
+        assert_eq!(reversed.decide("keep.log", false), Some(true));
+    }
+
+    #[test]
+    fn nested_rules() {
+        let ignore = rules("sub/dir", &["/local.txt", "*.tmp"]);
+        assert_eq!(ignore.decide("sub/dir/local.txt", false), Some(true));
+        assert_eq!(ignore.decide("sub/dir/deeper/local.txt", false), None);
+        assert_eq!(ignore.decide("sub/dir/deeper/x.tmp", false), Some(true));
+        // Outside of the directory holding the ignore file
+        assert_eq!(ignore.decide("sub/x.tmp", false), None);
+        assert_eq!(ignore.decide("sub/directory/x.tmp", false), None);
+    }
+
+    /// Paths packed from a tree of `(path, content)` files with `options`
+    fn packed(name: &str, files: &[(&str, &str)], options: &PackOptions) -> Vec<String> {
+        let dir = crate::scratch(&format!("pack_{}", name));
+        for (file, content) in files {
+            let path = dir.join("root").join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+        let out = dir.join("out.klu");
+        super::super::Archive::from_path_with(dir.join("root"), options)
+            .unwrap()
+            .write_to_path(&out)
+            .unwrap();
+        let mut paths = crate::read::Archive::from_path(&out).unwrap().paths();
+        std::fs::remove_dir_all(dir).unwrap();
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn nested_ignore_files() {
+        let files = [
+            (".gitignore", "*.log\n!keep.log\n"),
+            ("a.log", ""),
+            ("keep.log", ""),
+            // The deeper file overrides the negation above it
+            ("sub/.kluignore", "keep.log\n"),
+            ("sub/b.log", ""),
+            ("sub/keep.log", ""),
+            ("sub/c.txt", ""),
+        ];
+        let options = PackOptions::new().ignore_files(true).hidden(false);
+        assert_eq!(
+            packed("ignores", &files, &options),
+            ["root/", "root/keep.log", "root/sub/", "root/sub/c.txt"]
+        );
+    }
+
+    #[test]
+    fn prune_empty_directories() {
+        let files = [
+            ("a.rs", ""),
+            ("docs/readme.md", ""),
+            ("src/lib.rs", ""),
+            ("src/assets/logo.png", ""),
+        ];
+        let paths = packed("prune", &files, &PackOptions::new().include("*.rs"));
+        assert_eq!(paths, ["root/", "root/a.rs", "root/src/", "root/src/lib.rs"]);
+        // Without include patterns, directories are kept even when excluding their content
+        let paths = packed("keep", &files, &PackOptions::new().exclude("*.md"));
+        assert!(paths.contains(&"root/docs/".to_owned()));
+    }
+}
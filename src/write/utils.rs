@@ -1,12 +1,65 @@
 pub fn u64_to_slice(inp: u64) -> Box<[u8]> {
     let mut out = vec![0x00u8; 8];
-    out[0x00] = (inp >> 0x38) as u8 & 0xFF;
-    out[0x01] = (inp >> 0x30) as u8 & 0xFF;
-    out[0x02] = (inp >> 0x28) as u8 & 0xFF;
-    out[0x03] = (inp >> 0x20) as u8 & 0xFF;
-    out[0x04] = (inp >> 0x18) as u8 & 0xFF;
-    out[0x05] = (inp >> 0x10) as u8 & 0xFF;
-    out[0x06] = (inp >> 0x08) as u8 & 0xFF;
-    out[0x07] = (inp >> 0x00) as u8 & 0xFF;
+    out[0x00] = (inp >> 0x38) as u8;
+    out[0x01] = (inp >> 0x30) as u8;
+    out[0x02] = (inp >> 0x28) as u8;
+    out[0x03] = (inp >> 0x20) as u8;
+    out[0x04] = (inp >> 0x18) as u8;
+    out[0x05] = (inp >> 0x10) as u8;
+    out[0x06] = (inp >> 0x08) as u8;
+    out[0x07] = inp as u8;
     out.into_boxed_slice()
 }
+
+/// Registration flag: the file's data is a sparse map followed by its data extents
+pub const FLAG_SPARSE: u8 = 0b0000_0001;
+/// Registration flag (version 2): the size is followed by the absolute offset of the data
+pub const FLAG_OFFSET: u8 = 0b0000_0010;
+
+/// Length of the footer ending append-only archives
+pub const FOOTER_LEN: usize = 8 + 8 + 4;
+/// Last bytes of append-only archives
+pub const FOOTER_MAGIC: [u8; 4] = *b"KLUI";
+/// Last bytes of a file an archive has been appended to, after the offset of the archive
+pub const TRAILER_MAGIC: [u8; 4] = *b"KLUE";
+
+/// Returns the `(offset, length)` of every data extent of `file`, or [None] if the filesystem
+/// can't tell holes apart from data
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+pub fn data_extents(file: &std::fs::File, len: u64) -> std::io::Result<Option<Vec<(u64, u64)>>> {
+    use std::os::unix::io::AsRawFd;
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        // SAFETY: `fd` is a valid descriptor borrowed from `file` for the whole loop
+        let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = std::io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // No data after `offset`, the rest of the file is a hole
+                Some(libc::ENXIO) => break,
+                Some(libc::EINVAL) => Ok(None),
+                _ => Err(err),
+            };
+        }
+        // SAFETY: same as above
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let (data, hole) = (data as u64, (hole as u64).min(len));
+        if data >= hole {
+            break;
+        }
+        extents.push((data, hole - data));
+        offset = hole;
+    }
+    Ok(Some(extents))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn data_extents(_file: &std::fs::File, _len: u64) -> std::io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
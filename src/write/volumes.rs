@@ -0,0 +1,79 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The path of the volume `number` of the archive at `path`: `<path>.001` for the first one
+pub(crate) fn volume_path(path: &Path, number: usize) -> PathBuf {
+    let mut volume = path.as_os_str().to_owned();
+    volume.push(format!(".{:03}", number));
+    PathBuf::from(volume)
+}
+
+/// A writer splitting what it is given into volumes of at most `max` bytes, a volume being
+/// created once the previous one is full
+pub(crate) struct Volumes {
+    path: PathBuf,
+    max: u64,
+    current: Option<std::fs::File>,
+    /// Bytes written to the current volume
+    written: u64,
+    /// Paths of the volumes created so far
+    pub(crate) paths: Vec<PathBuf>,
+}
+
+impl Volumes {
+    pub(crate) fn new(path: &Path, max: u64) -> Self {
+        Volumes {
+            path: path.to_path_buf(),
+            max,
+            current: None,
+            written: 0,
+            paths: Vec::new(),
+        }
+    }
+
+    /// Remove the volumes following the last one written, left by a previous archive split
+    /// into more volumes, which would be read along with them
+    pub(crate) fn remove_stale(&self) -> std::io::Result<()> {
+        let mut number = self.paths.len() + 1;
+        loop {
+            match std::fs::remove_file(volume_path(&self.path, number)) {
+                Ok(()) => number += 1,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Write for Volumes {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.current.is_none() || self.written == self.max {
+            if let Some(mut full) = self.current.take() {
+                full.flush()?;
+            }
+            let path = volume_path(&self.path, self.paths.len() + 1);
+            self.current = Some(std::fs::File::create(&path)?);
+            self.paths.push(path);
+            self.written = 0;
+        }
+        let left = self.max - self.written;
+        let n = buf.len().min(std::convert::TryFrom::try_from(left).unwrap_or(usize::MAX));
+        let n = self
+            .current
+            .as_mut()
+            .expect("A volume has just been opened")
+            .write(&buf[..n])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.current {
+            Some(file) => file.flush(),
+            None => Ok(()),
+        }
+    }
+}
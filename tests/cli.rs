@@ -0,0 +1,168 @@
+//! Run the `klu` tool the way a user would
+use common::scratch;
+use std::path::Path;
+use std::process::{Command, Output};
+
+mod common;
+
+fn klu(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_klu"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Stdout of a command that must succeed
+fn ok(dir: &Path, args: &[&str]) -> String {
+    let output = klu(dir, args);
+    assert!(
+        output.status.success(),
+        "klu {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Stderr of a command that must fail
+fn fails(dir: &Path, args: &[&str]) -> String {
+    let output = klu(dir, args);
+    assert!(!output.status.success(), "klu {:?} succeeded", args);
+    String::from_utf8(output.stderr).unwrap()
+}
+
+/// `site/index.html`, `site/css/main.css` and the empty `site/img/`
+fn site(dir: &Path) {
+    std::fs::create_dir_all(dir.join("site/css")).unwrap();
+    std::fs::create_dir_all(dir.join("site/img")).unwrap();
+    std::fs::write(dir.join("site/index.html"), "<html>").unwrap();
+    std::fs::write(dir.join("site/css/main.css"), "body {}").unwrap();
+}
+
+#[test]
+fn create_list_cat() {
+    let dir = scratch("cli_create_list_cat");
+    site(&dir);
+    ok(&dir, &["create", "site"]);
+    let mut listed = ok(&dir, &["list", "site.klu"])
+        .lines()
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    listed.sort();
+    assert_eq!(
+        listed,
+        [
+            "site/",
+            "site/css/",
+            "site/css/main.css",
+            "site/img/",
+            "site/index.html"
+        ]
+    );
+    let long = ok(&dir, &["list", "-l", "site.klu"]);
+    assert!(long.contains("           6 site/index.html\n"), "{}", long);
+    let json = ok(&dir, &["list", "--json", "site.klu"]);
+    assert!(json.contains(r#"{"path":"site/css/main.css","type":"file","size":7}"#));
+    assert_eq!(ok(&dir, &["cat", "site.klu", "site/index.html"]), "<html>");
+    // The root's name can be left out
+    assert_eq!(ok(&dir, &["cat", "site.klu", "css/main.css"]), "body {}");
+    assert!(fails(&dir, &["cat", "site.klu", "missing"]).contains("no file at missing"));
+    ok(
+        &dir,
+        &["create", "site/css", "site/index.html", "-o", "two.klu"],
+    );
+    let listed = ok(&dir, &["list", "two.klu"]);
+    assert!(listed.contains("css/main.css\n") && listed.contains("index.html\n"));
+    assert!(fails(&dir, &["create", "site/css", "site/img"]).contains("needs -o"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn extract() {
+    let dir = scratch("cli_extract");
+    site(&dir);
+    ok(&dir, &["create", "site", "-o", "site.klu"]);
+    ok(&dir, &["extract", "site.klu", "-C", "all"]);
+    assert_eq!(
+        std::fs::read(dir.join("all/site/index.html")).unwrap(),
+        b"<html>"
+    );
+    assert!(dir.join("all/site/img").is_dir());
+    // Paths with or without the root's name land at their place inside the archive
+    ok(&dir, &["extract", "site.klu", "css/main.css", "-C", "some"]);
+    ok(
+        &dir,
+        &["extract", "site.klu", "site/index.html", "-C", "some"],
+    );
+    assert_eq!(
+        std::fs::read(dir.join("some/site/css/main.css")).unwrap(),
+        b"body {}"
+    );
+    assert_eq!(
+        std::fs::read(dir.join("some/site/index.html")).unwrap(),
+        b"<html>"
+    );
+    // Extracting a directory again
+    ok(&dir, &["extract", "site.klu", "css/", "-C", "some"]);
+    ok(&dir, &["extract", "site.klu", "css", "-C", "some"]);
+    assert!(fails(&dir, &["extract", "site.klu", "js", "-C", "some"]).contains("isn't in"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn inspect_and_diff() {
+    let dir = scratch("cli_inspect_and_diff");
+    site(&dir);
+    ok(&dir, &["create", "site", "-o", "old.klu"]);
+    let dump = ok(&dir, &["inspect", "old.klu"]);
+    assert!(dump.contains("index.html"), "{}", dump);
+    std::fs::write(dir.join("site/index.html"), "<html></html>").unwrap();
+    std::fs::remove_file(dir.join("site/css/main.css")).unwrap();
+    std::fs::write(dir.join("site/img/logo.png"), "png").unwrap();
+    let expected = "\
+removed  css/main.css (-7)
+added    img/logo.png (+3)
+modified index.html (6 -> 13, +7)
+3 changes, +3 bytes
+";
+    assert_eq!(ok(&dir, &["diff", "old.klu", "site"]), expected);
+    ok(&dir, &["create", "site", "-o", "new.klu"]);
+    assert_eq!(ok(&dir, &["diff", "old.klu", "new.klu"]), expected);
+    assert_eq!(ok(&dir, &["diff", "new.klu", "site"]), "");
+    // Cut in the middle of the data
+    let bytes = std::fs::read(dir.join("old.klu")).unwrap();
+    std::fs::write(dir.join("cut.klu"), &bytes[..bytes.len() - 3]).unwrap();
+    assert!(fails(&dir, &["inspect", "cut.klu"]).contains("inconsistencies found"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn usage() {
+    let dir = scratch("cli_usage");
+    let output = klu(&dir, &["frobnicate"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Usage:"));
+    assert!(ok(&dir, &["help"]).starts_with("Usage:"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn escaping_names() {
+    let dir = scratch("cli_escaping_names");
+    std::fs::create_dir_all(dir.join("zs/x")).unwrap();
+    // As long as the name it is renamed to below
+    std::fs::write(dir.join("zs/x/aaaaaaaaaaa"), "pwned").unwrap();
+    ok(&dir, &["create", "zs/x", "-o", "zs.klu"]);
+    for name in &[&b"../../pwned"[..], b"..\\..\\pwned", b"aaaaa\0aaaaa"] {
+        let mut bytes = std::fs::read(dir.join("zs.klu")).unwrap();
+        let at = bytes.windows(11).position(|w| w == b"aaaaaaaaaaa").unwrap();
+        bytes[at..at + 11].copy_from_slice(name);
+        std::fs::write(dir.join("bad.klu"), bytes).unwrap();
+        let err = fails(&dir, &["extract", "bad.klu", "-C", "zs/out/x"]);
+        assert!(err.contains("isn't a single path component"), "{}", err);
+        assert!(!dir.join("zs/out/pwned").exists());
+        assert!(!dir.join("zs/pwned").exists());
+    }
+    std::fs::remove_dir_all(dir).unwrap();
+}
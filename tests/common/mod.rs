@@ -0,0 +1,11 @@
+//! Helpers shared by the integration tests
+#![allow(dead_code)]
+use std::path::PathBuf;
+
+/// A fresh directory for the test `name`
+pub fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("klu_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
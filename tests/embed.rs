@@ -0,0 +1,97 @@
+//! Embed a directory in a crate with the build script helpers, the way a user would
+use common::scratch;
+use klu_core::read;
+use std::path::Path;
+use std::process::Command;
+
+mod common;
+
+const BUILD_SCRIPT: &str = r#"
+fn main() {
+    klu_core::build::embed_dir("assets", "assets").expect("Unable to pack assets");
+}
+"#;
+
+const MAIN: &str = r#"
+fn main() {
+    let assets: klu_core::read::Archive = klu_core::include_archive!("assets");
+    let mut paths = assets.paths();
+    paths.sort();
+    println!("{:?}", paths);
+    println!("{}", std::str::from_utf8(assets.bytes("assets/sub/b").unwrap()).unwrap());
+}
+"#;
+
+/// A crate depending on this one, embedding its `assets` directory
+fn user_crate(dir: &Path) {
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    std::fs::create_dir_all(dir.join("assets/sub")).unwrap();
+    std::fs::write(dir.join("assets/sub/a"), "first").unwrap();
+    std::fs::write(dir.join("assets/sub/b"), "second").unwrap();
+    let manifest = format!(
+        "[package]\nname = \"embedding\"\nversion = \"0.0.0\"\nedition = \"2018\"\n\n\
+         [dependencies]\nklu_core = {{ path = {:?} }}\n\n\
+         [build-dependencies]\nklu_core = {{ path = {:?} }}\n\n[workspace]\n",
+        env!("CARGO_MANIFEST_DIR"),
+        env!("CARGO_MANIFEST_DIR")
+    );
+    std::fs::write(dir.join("Cargo.toml"), manifest).unwrap();
+    std::fs::write(dir.join("build.rs"), BUILD_SCRIPT).unwrap();
+    std::fs::write(dir.join("src/main.rs"), MAIN).unwrap();
+}
+
+#[test]
+fn embed_dir() {
+    let dir = scratch("embed_dir");
+    user_crate(&dir);
+    let output = Command::new(std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into()))
+        .current_dir(&dir)
+        .args(["run", "--quiet", "--offline"])
+        .env("CARGO_TARGET_DIR", dir.join("target"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    // `include_archive!` opens the archive packed by the build script
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[\"assets/\", \"assets/sub/\", \"assets/sub/a\", \"assets/sub/b\"]\nsecond\n"
+    );
+
+    // What the build script printed, and the archive it wrote in its OUT_DIR
+    let build = std::fs::read_dir(dir.join("target/debug/build"))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.join("output").exists())
+        .unwrap();
+    let printed = std::fs::read_to_string(build.join("output")).unwrap();
+    // The sources are canonicalized when packing
+    let root = dir.canonicalize().unwrap();
+    let mut rerun = printed
+        .lines()
+        .filter_map(|line| line.strip_prefix("cargo:rerun-if-changed="))
+        .map(|path| Path::new(path).strip_prefix(&root).unwrap())
+        .collect::<Vec<_>>();
+    rerun.sort_unstable();
+    // Every file and directory, so adding or removing one also triggers a rebuild
+    assert_eq!(
+        rerun,
+        [
+            Path::new("assets"),
+            Path::new("assets/sub"),
+            Path::new("assets/sub/a"),
+            Path::new("assets/sub/b"),
+        ]
+    );
+    let archive = read::Archive::from_path(build.join("out/assets.klu")).unwrap();
+    assert_eq!(archive.paths().len(), 4);
+    let extracted = dir.join("a");
+    assert!(archive
+        .extract_file(Path::new("assets/sub/a"), &extracted)
+        .unwrap());
+    assert_eq!(std::fs::read(extracted).unwrap(), b"first");
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,42 @@
+//! Opening a directory must stay linear in its number of childs, quadratic parsing takes
+//! minutes here
+use klu_core::read::Archive;
+
+const CHILDS: usize = 200_000;
+
+fn record(name: &str, is_file: bool, size: u64) -> Vec<u8> {
+    let mut record = vec![(name.len() << 1) as u8 | is_file as u8, 0];
+    record.extend_from_slice(&size.to_be_bytes());
+    record.extend_from_slice(name.as_bytes());
+    record
+}
+
+/// An archive whose root holds `CHILDS` one byte files
+fn large_archive() -> Vec<u8> {
+    let mut header = Vec::new();
+    for i in 0..CHILDS {
+        header.extend(record(&format!("file{:06}", i), true, 1));
+    }
+    let dir_size = 8 + header.len() as u64 + CHILDS as u64;
+    let root = record("root", false, dir_size);
+    let mut archive = b"KLU\x01".to_vec();
+    archive.extend_from_slice(&(root.len() as u64).to_be_bytes());
+    archive.extend_from_slice(&(4 + 8 + 8 + root.len() as u64 + dir_size).to_be_bytes());
+    archive.extend(root);
+    archive.extend_from_slice(&(header.len() as u64).to_be_bytes());
+    archive.extend(header);
+    archive.extend((0..CHILDS).map(|i| i as u8));
+    archive
+}
+
+#[test]
+fn large_directory() {
+    let archive = large_archive();
+    let archive = Archive::from_bytes(archive).unwrap();
+    assert!(archive.path_exist(format!("root/file{:06}", CHILDS - 1)));
+    assert_eq!(archive.index().unwrap().len(), CHILDS + 1);
+    assert_eq!(
+        archive.bytes("root/file000300"),
+        Some(&[300_usize as u8][..])
+    );
+}
@@ -0,0 +1,282 @@
+//! Write archives with each layout, then read them back
+use common::scratch;
+use klu_core::{edit, read, write};
+use std::path::Path;
+
+mod common;
+
+/// Create `files` (with their content) under `root`
+fn tree(root: &Path, files: &[(&str, &[u8])]) {
+    for (file, content) in files {
+        let path = root.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+}
+
+fn content(archive: &read::Archive, path: &str) -> Vec<u8> {
+    let mut content = Vec::new();
+    assert!(archive.extract_to(path, &mut content).unwrap(), "{}", path);
+    content
+}
+
+/// The paths of the archive, whose entries are in the order the directories were read in
+fn sorted(archive: &read::Archive) -> Vec<String> {
+    let mut paths = archive.paths();
+    paths.sort();
+    paths
+}
+
+fn assert_consistent(path: &Path) {
+    let mut archive = std::fs::File::open(path).unwrap();
+    let found = read::inspect(&mut archive, &mut std::io::sink()).unwrap();
+    assert!(found.is_empty(), "{:?}", found);
+}
+
+#[test]
+fn indexed_then_appended() {
+    let dir = scratch("indexed_then_appended");
+    tree(&dir.join("root"), &[("a", b"first"), ("sub/b", b"second")]);
+    std::fs::write(dir.join("added"), b"added").unwrap();
+    let out = dir.join("out.klu");
+    write::Archive::from_path(dir.join("root"))
+        .unwrap()
+        .write_indexed_to_path(&out)
+        .unwrap();
+    let len = std::fs::metadata(&out).unwrap().len();
+    let before = std::fs::read(&out).unwrap();
+
+    let mut editor = edit::Editor::open(&out).unwrap();
+    editor.add("root/sub/c", dir.join("added")).unwrap();
+    editor.add("root/d", dir.join("added")).unwrap();
+    drop(editor);
+    // Only appended to
+    let after = std::fs::read(&out).unwrap();
+    assert!(after.len() as u64 > len);
+    assert_eq!(after[..before.len()], before[..]);
+
+    let archive = read::Archive::from_path(&out).unwrap();
+    assert_eq!(
+        sorted(&archive),
+        [
+            "root/",
+            "root/a",
+            "root/d",
+            "root/sub/",
+            "root/sub/b",
+            "root/sub/c"
+        ]
+    );
+    assert_eq!(content(&archive, "root/a"), b"first");
+    assert_eq!(content(&archive, "root/sub/b"), b"second");
+    assert_eq!(content(&archive, "root/sub/c"), b"added");
+    assert_eq!(content(&archive, "root/d"), b"added");
+    assert_consistent(&out);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn appended_to_a_file() {
+    let dir = scratch("appended_to_a_file");
+    tree(&dir.join("root"), &[("a", b"first"), ("sub/b", b"second")]);
+    let host = dir.join("host");
+    let prefix = b"#!/bin/sh\necho not an archive\nexit 0\n".to_vec();
+    std::fs::write(&host, &prefix).unwrap();
+    write::Archive::from_path(dir.join("root"))
+        .unwrap()
+        .append_to_path(&host)
+        .unwrap();
+    // The file it was appended to is left as it was
+    assert!(std::fs::read(&host).unwrap().starts_with(&prefix));
+
+    let archive = read::Archive::from_path(&host).unwrap();
+    assert_eq!(
+        sorted(&archive),
+        ["root/", "root/a", "root/sub/", "root/sub/b"]
+    );
+    assert_eq!(content(&archive, "root/sub/b"), b"second");
+    let out = dir.join("released");
+    std::fs::create_dir(&out).unwrap();
+    archive.release(&out).unwrap();
+    assert_eq!(std::fs::read(out.join("root/a")).unwrap(), b"first");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn failed_append() {
+    let dir = scratch("failed_append");
+    // Large enough for part of it to be written before the change is noticed
+    let large = vec![7; 100_000];
+    tree(&dir.join("root"), &[("large", &large)]);
+    let host = dir.join("host");
+    std::fs::write(&host, b"host").unwrap();
+    let archive = write::Archive::from_path(dir.join("root")).unwrap();
+    std::fs::write(dir.join("root/large"), [&large[..], b"grown"].concat()).unwrap();
+    assert!(matches!(
+        archive.append_to_path(&host),
+        Err(write::WriteError::SourceChanged(_))
+    ));
+    assert_eq!(std::fs::read(&host).unwrap(), b"host");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Set when [appended_to_the_executable] runs a copy of this test binary
+const CHILD: &str = "KLU_TEST_EMBEDDED_CHILD";
+
+#[test]
+fn appended_to_the_executable() {
+    if std::env::var_os(CHILD).is_some() {
+        let archive = read::Archive::from_current_exe().unwrap();
+        assert_eq!(content(&archive, "payload/data"), b"carried along");
+        println!("payload found");
+        return;
+    }
+    // Without anything appended
+    assert!(read::Archive::from_current_exe().is_err());
+
+    let dir = scratch("appended_to_the_executable");
+    tree(&dir.join("payload"), &[("data", b"carried along")]);
+    let exe = dir.join("installer");
+    std::fs::copy(std::env::current_exe().unwrap(), &exe).unwrap();
+    write::Archive::from_path(dir.join("payload"))
+        .unwrap()
+        .append_to_path(&exe)
+        .unwrap();
+    let output = std::process::Command::new(&exe)
+        .args(["--exact", "appended_to_the_executable", "--nocapture"])
+        .env(CHILD, "1")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(stdout.contains("payload found"), "{}", stdout);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn volumes() {
+    let dir = scratch("volumes");
+    let large = (0..3000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    tree(&dir.join("root"), &[("a", b"first"), ("large", &large)]);
+    let archive = write::Archive::from_path(dir.join("root")).unwrap();
+    let out = dir.join("out.klu");
+    let volumes = archive.write_volumes_to_path(&out, 1000).unwrap();
+    assert_eq!(volumes.len(), 4);
+    assert_eq!(volumes[0], dir.join("out.klu.001"));
+    assert_eq!(volumes[3], dir.join("out.klu.004"));
+    for volume in &volumes[..3] {
+        assert_eq!(std::fs::metadata(volume).unwrap().len(), 1000);
+    }
+
+    // Only the file named is read, which ends before the data of `large`
+    let first = read::Archive::from_path(&volumes[0]).unwrap();
+    assert!(first.extract_to("root/large", &mut Vec::new()).is_err());
+    assert!(read::Archive::from_volumes(&out).is_err());
+    let read = read::Archive::from_volumes(&volumes[0]).unwrap();
+    assert_eq!(content(&read, "root/a"), b"first");
+    // From the first volume to the last one
+    assert_eq!(content(&read, "root/large"), large);
+    #[cfg(feature = "virtual_fs")]
+    {
+        use std::io::{Read, Seek, SeekFrom};
+        let mut file = read.get_virtual("root/large").unwrap();
+        // Across the boundary between the second and third volumes
+        let mut buf = vec![0; 200];
+        let start = 2000 - 100 - data_offset(&volumes, &large);
+        file.seek(SeekFrom::Start(start)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, large[start as usize..start as usize + 200]);
+    }
+
+    // Fewer volumes, the last ones of the previous set are removed
+    let volumes = archive.write_volumes_to_path(&out, 2000).unwrap();
+    assert_eq!(volumes.len(), 2);
+    assert!(!dir.join("out.klu.003").exists());
+    assert!(!dir.join("out.klu.004").exists());
+    let read = read::Archive::from_volumes(&volumes[0]).unwrap();
+    assert_eq!(content(&read, "root/large"), large);
+    // A missing volume
+    std::fs::remove_file(&volumes[1]).unwrap();
+    assert!(read::Archive::from_volumes(&volumes[0]).is_err());
+
+    // None of the volumes is left behind when writing fails
+    std::fs::write(dir.join("root/large"), [&large[..], b"grown"].concat()).unwrap();
+    assert!(matches!(
+        archive.write_volumes_to_path(dir.join("failed.klu"), 1000),
+        Err(write::WriteError::SourceChanged(_))
+    ));
+    assert!(!dir.join("failed.klu.001").exists());
+    assert!(!dir.join("failed.klu.004").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Offset of `data` in the archive split into `volumes`
+#[cfg(feature = "virtual_fs")]
+fn data_offset(volumes: &[std::path::PathBuf], data: &[u8]) -> u64 {
+    let bytes = volumes
+        .iter()
+        .flat_map(|volume| std::fs::read(volume).unwrap())
+        .collect::<Vec<_>>();
+    let at = bytes
+        .windows(data.len())
+        .position(|window| window == data)
+        .unwrap();
+    at as u64
+}
+
+#[test]
+fn several_roots() {
+    let dir = scratch("several_roots");
+    tree(
+        &dir.join("textures"),
+        &[("a.png", b"png"), ("sub/b.png", b"b")],
+    );
+    tree(&dir.join("sounds"), &[("a.ogg", b"ogg")]);
+    std::fs::write(dir.join("readme"), b"read me").unwrap();
+    let out = dir.join("out.klu");
+    write::Archive::from_paths(&[dir.join("textures"), dir.join("sounds"), dir.join("readme")])
+        .unwrap()
+        .write_to_path(&out)
+        .unwrap();
+
+    let archive = read::Archive::from_path(&out).unwrap();
+    assert_eq!(archive.root_name(), "");
+    assert_eq!(
+        sorted(&archive),
+        [
+            "readme",
+            "sounds/",
+            "sounds/a.ogg",
+            "textures/",
+            "textures/a.png",
+            "textures/sub/",
+            "textures/sub/b.png",
+        ]
+    );
+    assert!(archive.path_exist("textures/sub/b.png"));
+    assert!(!archive.path_exist("sub/b.png"));
+    assert_eq!(content(&archive, "sounds/a.ogg"), b"ogg");
+    assert_eq!(content(&archive, "readme"), b"read me");
+    let entry = archive.entry("textures/sub").unwrap().unwrap();
+    assert_eq!(entry.path, "textures/sub/");
+    // The top-level entries are in the order of their paths
+    assert_eq!(entry.indices[0], 0);
+    assert_eq!(archive.entry("readme").unwrap().unwrap().indices, [2]);
+    assert_consistent(&out);
+
+    let released = dir.join("released");
+    std::fs::create_dir(&released).unwrap();
+    archive.release(&released).unwrap();
+    assert_eq!(
+        std::fs::read(released.join("textures/sub/b.png")).unwrap(),
+        b"b"
+    );
+    assert_eq!(std::fs::read(released.join("readme")).unwrap(), b"read me");
+
+    // Two top-level entries can't have the same name
+    tree(&dir.join("other"), &[("textures/c", b"c")]);
+    assert!(
+        write::Archive::from_paths(&[dir.join("textures"), dir.join("other/textures")]).is_err()
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
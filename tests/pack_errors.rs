@@ -0,0 +1,119 @@
+//! What packing does with entries it can't read, and with files changing before being written
+use common::scratch;
+use klu_core::write::{Archive, ErrorPolicy, PackOptions, WriteError};
+use std::path::{Path, PathBuf};
+
+mod common;
+
+/// A name longer than the format allows, so `root/bad/` can't be packed
+fn unpackable(name: &str) -> PathBuf {
+    let root = scratch(name).join("root");
+    std::fs::create_dir_all(root.join("bad")).unwrap();
+    std::fs::create_dir_all(root.join("good")).unwrap();
+    std::fs::write(root.join("good/file"), "content").unwrap();
+    std::fs::write(root.join("bad").join("x".repeat(200)), "").unwrap();
+    root
+}
+
+/// The paths of `archive`, written next to `root`
+fn paths(archive: &Archive, root: &Path) -> Vec<String> {
+    let out = root.with_file_name("out.klu");
+    archive.write_to_path(&out).unwrap();
+    let mut paths = klu_core::read::Archive::from_path(&out).unwrap().paths();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn fail_fast() {
+    let root = unpackable("fail_fast");
+    let err = Archive::from_path(&root).unwrap_err();
+    assert!(matches!(err, WriteError::InvalidInput(_)), "{:?}", err);
+    assert!(err.to_string().contains(&"x".repeat(200)), "{}", err);
+    std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn fail_fast_names_the_entry() {
+    let dir = scratch("fail_fast_names_the_entry");
+    let missing = dir.join("missing");
+    let err = Archive::from_path(&missing).unwrap_err();
+    match &err {
+        WriteError::IoError(e) => assert_eq!(e.kind(), std::io::ErrorKind::NotFound),
+        err => panic!("{:?}", err),
+    }
+    assert!(
+        err.to_string().contains(&*missing.to_string_lossy()),
+        "{}",
+        err
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn skip() {
+    let root = unpackable("skip");
+    let options = PackOptions::new().on_error(ErrorPolicy::Skip);
+    let archive = Archive::from_path_with(&root, &options).unwrap();
+    let skipped = archive.skipped();
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].0, root.join("bad").join("x".repeat(200)));
+    assert_eq!(
+        paths(&archive, &root),
+        ["root/", "root/bad/", "root/good/", "root/good/file"]
+    );
+    std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn callback() {
+    let root = unpackable("callback");
+    // Skip nothing
+    let options = PackOptions::new().on_error(ErrorPolicy::Callback(Box::new(|_, _| false)));
+    assert!(Archive::from_path_with(&root, &options).is_err());
+    // Skip everything, seeing which entry failed
+    let bad = root.join("bad");
+    let options = PackOptions::new().on_error(ErrorPolicy::Callback(Box::new(
+        move |path: &Path, err: &WriteError| {
+            assert!(matches!(err, WriteError::InvalidInput(_)));
+            path.parent() == Some(&bad)
+        },
+    )));
+    let archive = Archive::from_path_with(&root, &options).unwrap();
+    assert_eq!(archive.skipped().len(), 1);
+    assert_eq!(
+        paths(&archive, &root),
+        ["root/", "root/bad/", "root/good/", "root/good/file"]
+    );
+    std::fs::remove_dir_all(root.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn source_changed() {
+    let dir = scratch("source_changed");
+    std::fs::create_dir(dir.join("root")).unwrap();
+    std::fs::write(dir.join("root/file"), "scanned").unwrap();
+    std::fs::write(dir.join("root/small"), "small").unwrap();
+    let options = PackOptions::new().snapshot_below(6);
+    let archive = Archive::from_path_with(dir.join("root"), &options).unwrap();
+    // The snapshot isn't read again
+    std::fs::write(dir.join("root/small"), "changed").unwrap();
+    let out = dir.join("out.klu");
+    archive.write_to_path(&out).unwrap();
+    let read = klu_core::read::Archive::from_path(&out).unwrap();
+    let small = dir.join("small");
+    assert!(read.extract_file(Path::new("root/small"), &small).unwrap());
+    assert_eq!(std::fs::read(small).unwrap(), b"small");
+    std::fs::write(dir.join("root/file"), "grown since").unwrap();
+    let err = archive.write_to_path(&out).unwrap_err();
+    assert!(matches!(err, WriteError::SourceChanged(_)), "{:?}", err);
+    // No truncated archive is left behind
+    assert!(!out.exists());
+    std::fs::write(dir.join("root/file"), "short").unwrap();
+    assert!(matches!(
+        archive.write_indexed_to_path(&out),
+        Err(WriteError::SourceChanged(_))
+    ));
+    assert!(!out.exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
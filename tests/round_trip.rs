@@ -0,0 +1,307 @@
+//! Pack trees mixing files and directories, then read them back
+use common::scratch;
+use klu_core::{read, write};
+use std::path::Path;
+
+mod common;
+
+/// Create `files` (with their path as content) and `dirs` under `root`
+fn tree(root: &Path, files: &[&str], dirs: &[&str]) {
+    for dir in dirs {
+        std::fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    for file in files {
+        let path = root.join(file);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, file.repeat(3)).unwrap();
+    }
+}
+
+fn pack(root: &Path, out: &Path) -> read::Archive {
+    // Sorting puts `a.txt` before the `b` directory, the case where headers were read from the
+    // wrong offset
+    let options = write::PackOptions::new().reproducible(true);
+    write::Archive::from_path_with(root, &options)
+        .unwrap()
+        .write_to_path(out)
+        .unwrap();
+    read::Archive::from_path(out).unwrap()
+}
+
+/// Assert both trees hold the same directories and files
+fn assert_same(expected: &Path, actual: &Path) {
+    let mut names = std::fs::read_dir(expected)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect::<Vec<_>>();
+    names.sort();
+    let mut actual_names = std::fs::read_dir(actual)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect::<Vec<_>>();
+    actual_names.sort();
+    assert_eq!(names, actual_names, "in {}", actual.display());
+    for name in names {
+        let (e, a) = (expected.join(&name), actual.join(&name));
+        if e.is_dir() {
+            assert!(a.is_dir(), "{} isn't a directory", a.display());
+            assert_same(&e, &a);
+        } else {
+            assert_eq!(std::fs::read(&e).unwrap(), std::fs::read(&a).unwrap());
+        }
+    }
+}
+
+#[test]
+fn files_before_directories() {
+    let dir = scratch("files_before_directories");
+    let root = dir.join("root");
+    tree(
+        &root,
+        &[
+            "a.txt",
+            "b/c.txt",
+            "b/d/e.txt",
+            "b/f.txt",
+            "g.txt",
+            "h/i/j/k.txt",
+        ],
+        &["b/d/empty"],
+    );
+    let archive = pack(&root, &dir.join("out.klu"));
+    let mut paths = archive.paths();
+    paths.sort();
+    assert_eq!(
+        paths,
+        [
+            "root/",
+            "root/a.txt",
+            "root/b/",
+            "root/b/c.txt",
+            "root/b/d/",
+            "root/b/d/e.txt",
+            "root/b/d/empty/",
+            "root/b/f.txt",
+            "root/g.txt",
+            "root/h/",
+            "root/h/i/",
+            "root/h/i/j/",
+            "root/h/i/j/k.txt",
+        ]
+    );
+    std::fs::create_dir(dir.join("out")).unwrap();
+    archive.release(dir.join("out")).unwrap();
+    assert_same(&root, &dir.join("out/root"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn extract_nested_entries() {
+    let dir = scratch("extract_nested_entries");
+    let root = dir.join("root");
+    tree(&root, &["1", "2/3", "2/4/5", "6", "7/8"], &[]);
+    let archive = pack(&root, &dir.join("out.klu"));
+    let out = dir.join("out");
+    std::fs::create_dir(&out).unwrap();
+    assert!(archive
+        .extract_file(Path::new("root/2/4/5"), &out.join("5"))
+        .unwrap());
+    assert_eq!(std::fs::read(out.join("5")).unwrap(), b"2/4/52/4/52/4/5");
+    assert!(archive.extract_file(Path::new("root/2"), &out).unwrap());
+    assert_same(&root.join("2"), &out.join("2"));
+    assert!(!archive.extract_file(Path::new("root/2/5"), &out).unwrap());
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn read_dir_order() {
+    let dir = scratch("read_dir_order");
+    let root = dir.join("root");
+    tree(&root, &["x/y", "z", "w/v/u", "t"], &["s", "r/q"]);
+    write::Archive::from_path(&root)
+        .unwrap()
+        .write_to_path(dir.join("out.klu"))
+        .unwrap();
+    let archive = read::Archive::from_path(dir.join("out.klu")).unwrap();
+    assert_eq!(archive.index().unwrap().len(), 11);
+    std::fs::create_dir(dir.join("out")).unwrap();
+    archive.release(dir.join("out")).unwrap();
+    assert_same(&root, &dir.join("out/root"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn sparse_file() {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::fs::MetadataExt;
+    const LEN: u64 = 8 << 20;
+    let dir = scratch("sparse_file");
+    let root = dir.join("root");
+    std::fs::create_dir(&root).unwrap();
+    let mut file = std::fs::File::create(root.join("sparse")).unwrap();
+    file.set_len(LEN).unwrap();
+    for offset in &[0, 3 << 20, LEN - 4096] {
+        file.seek(SeekFrom::Start(*offset)).unwrap();
+        file.write_all(&[0xA5; 4096]).unwrap();
+    }
+    drop(file);
+    // Not reproducible, as extents depend on the filesystem
+    write::Archive::from_path(&root)
+        .unwrap()
+        .write_to_path(dir.join("out.klu"))
+        .unwrap();
+    let archive = read::Archive::from_path(dir.join("out.klu")).unwrap();
+    // Only the extents are stored, not the holes between them
+    let size = std::fs::metadata(dir.join("out.klu")).unwrap().len();
+    assert!(size < 64 * 1024, "the archive takes {} bytes", size);
+    std::fs::create_dir(dir.join("out")).unwrap();
+    archive.release(dir.join("out")).unwrap();
+    let out = dir.join("out/root/sparse");
+    assert_eq!(
+        std::fs::read(&out).unwrap(),
+        std::fs::read(root.join("sparse")).unwrap()
+    );
+    // The holes are recreated rather than filled with zeros
+    let metadata = std::fs::metadata(&out).unwrap();
+    assert_eq!(metadata.len(), LEN);
+    assert!(
+        metadata.blocks() * 512 < LEN / 2,
+        "the extracted file uses {} blocks",
+        metadata.blocks()
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn reproducible() {
+    use std::time::{Duration, SystemTime};
+    let dir = scratch("reproducible");
+    let files = ["b/x", "a.txt", "c/d/e", "b/y", "z"];
+    // The same tree, created in another order and with other modification times
+    tree(&dir.join("first/root"), &files, &["c/empty"]);
+    let reversed = files.iter().rev().copied().collect::<Vec<_>>();
+    tree(&dir.join("second/root"), &reversed, &["c/empty"]);
+    for (i, file) in files.iter().enumerate() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 * i as u64);
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join("second/root").join(file))
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    }
+    pack(&dir.join("first/root"), &dir.join("first.klu"));
+    pack(&dir.join("second/root"), &dir.join("second.klu"));
+    assert_eq!(
+        std::fs::read(dir.join("first.klu")).unwrap(),
+        std::fs::read(dir.join("second.klu")).unwrap()
+    );
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn concurrent_reads() {
+    let dir = scratch("concurrent_reads");
+    let root = dir.join("root");
+    let files = (0..16).map(|i| format!("f{}", i)).collect::<Vec<_>>();
+    tree(
+        &root,
+        &files.iter().map(String::as_str).collect::<Vec<_>>(),
+        &[],
+    );
+    let archive = pack(&root, &dir.join("out.klu"));
+    std::thread::scope(|scope| {
+        for thread in 0..8 {
+            let (archive, files) = (&archive, &files);
+            let out = dir.join(format!("out{}", thread));
+            scope.spawn(move || {
+                // Each thread goes through the files in another order
+                for i in 0..files.len() * 20 {
+                    let file = &files[(i * (thread * 2 + 1)) % files.len()];
+                    assert!(archive
+                        .extract_file(Path::new("root").join(file), out.clone())
+                        .unwrap());
+                    assert_eq!(std::fs::read(&out).unwrap(), file.repeat(3).as_bytes());
+                }
+            });
+        }
+    });
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn mapped() {
+    let dir = scratch("mapped");
+    let root = dir.join("root");
+    tree(&root, &["a", "b"], &[]);
+    pack(&root, &dir.join("out.klu"));
+    // SAFETY: nothing else touches the scratch directory of the test
+    let archive = unsafe { read::Archive::from_path_mmap(dir.join("out.klu")) }.unwrap();
+    assert_eq!(archive.bytes("root/a"), Some(&b"aaa"[..]));
+    assert_eq!(archive.bytes("root/b"), Some(&b"bbb"[..]));
+    assert_eq!(archive.bytes("root"), None);
+    assert_eq!(archive.bytes("root/missing"), None);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn corrupted() {
+    let dir = scratch("corrupted");
+    let root = dir.join("root");
+    tree(&root, &["a", "b"], &[]);
+    pack(&root, &dir.join("out.klu"));
+    let bytes = std::fs::read(dir.join("out.klu")).unwrap();
+    // The data of `root/b` ends with the archive
+    let truncated = read::Archive::from_bytes(bytes[..bytes.len() - 2].to_vec()).unwrap();
+    let mut out = Vec::new();
+    assert!(matches!(
+        truncated.extract_to("root/b", &mut out),
+        Err(read::ReadError::InvalidArchive(_))
+    ));
+    // A name that isn't UTF-8, in the registration of `b`: a file named with 1 byte holding 3
+    let record = [3, 0, 0, 0, 0, 0, 0, 0, 0, 3, b'b'];
+    let name = bytes.windows(11).position(|w| w == record).unwrap() + 10;
+    let mut invalid = bytes;
+    invalid[name] = 0xFF;
+    let archive = read::Archive::from_bytes(invalid).unwrap();
+    assert!(matches!(
+        archive.entries(),
+        Err(read::ReadError::InvalidArchive(_))
+    ));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn lazily_parsed() {
+    let dir = scratch("lazily_parsed");
+    let root = dir.join("root");
+    tree(&root, &["a", "sub/x", "other/y"], &[]);
+    pack(&root, &dir.join("out.klu"));
+    let mut bytes = std::fs::read(dir.join("out.klu")).unwrap();
+    // The registration of `x` in the header of `sub`: a file named with 1 byte holding 15
+    let record = [3, 0, 0, 0, 0, 0, 0, 0, 0, 15, b'x'];
+    let x = bytes.windows(11).position(|w| w == record).unwrap();
+    // A directory whose name goes past the end of the header
+    bytes[x] = 0xFE;
+    let archive = read::Archive::from_bytes(bytes).unwrap();
+    let y = dir.join("y");
+    assert!(archive.extract_file(Path::new("root/other/y"), &y).unwrap());
+    assert_eq!(std::fs::read(y).unwrap(), b"other/yother/yother/y");
+    assert_eq!(archive.bytes("root/a"), Some(&b"aaa"[..]));
+    // Only reaching `sub` parses it
+    assert!(matches!(
+        archive.extract_file(Path::new("root/sub/x"), &dir.join("x")),
+        Err(read::ReadError::InvalidArchive(_))
+    ));
+    assert!(!archive.path_exist("root/sub/x"));
+    assert!(archive.path_exist("root/sub"));
+    assert!(archive.path_exist("other/y"));
+    // Unlike indexing every directory
+    assert!(matches!(
+        archive.index(),
+        Err(read::ReadError::InvalidArchive(_))
+    ));
+    std::fs::remove_dir_all(dir).unwrap();
+}
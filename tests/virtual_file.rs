@@ -0,0 +1,187 @@
+//! [VirtualFile] must behave like the [std::fs::File] it was packed from
+#![cfg(feature = "virtual_fs")]
+use klu_core::{read, write};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+mod common;
+
+/// Small deterministic generator, enough to pick operations
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        self.0 >> 33
+    }
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// A fresh directory for the test `name`, holding the `root` directory to pack
+fn scratch(name: &str) -> PathBuf {
+    let dir = common::scratch(name);
+    std::fs::create_dir(dir.join("root")).unwrap();
+    dir
+}
+
+/// Lines of varying length, so [BufRead::read_until] crosses buffer boundaries
+fn content(len: usize) -> Vec<u8> {
+    let mut rng = Lcg(len as u64);
+    let mut data = Vec::with_capacity(len);
+    while data.len() < len {
+        let line = rng.below(300) as usize;
+        data.extend((0..line).map(|i| b'a' + (i % 26) as u8));
+        data.push(b'\n');
+    }
+    data.truncate(len);
+    data
+}
+
+/// The outcome of an operation, errors compared by kind
+fn outcome<T>(result: std::io::Result<T>) -> Result<T, std::io::ErrorKind> {
+    result.map_err(|e| e.kind())
+}
+
+/// Run the same operations on `expected` and `actual`, comparing every result
+fn conform<E: BufRead + Seek, A: BufRead + Seek>(mut expected: E, mut actual: A, len: u64) {
+    let mut rng = Lcg(len + 1);
+    for step in 0..2000 {
+        let span = len as i64 + 100;
+        let (e, a) = match rng.below(7) {
+            0 => {
+                let n = rng.below(len + 100);
+                (
+                    outcome(expected.seek(SeekFrom::Start(n))),
+                    outcome(actual.seek(SeekFrom::Start(n))),
+                )
+            }
+            1 => {
+                let n = rng.below(2 * span as u64) as i64 - span;
+                (
+                    outcome(expected.seek(SeekFrom::Current(n))),
+                    outcome(actual.seek(SeekFrom::Current(n))),
+                )
+            }
+            2 => {
+                let n = rng.below(2 * span as u64) as i64 - span;
+                (
+                    outcome(expected.seek(SeekFrom::End(n))),
+                    outcome(actual.seek(SeekFrom::End(n))),
+                )
+            }
+            3 | 4 => {
+                // Up to twice the internal buffer, to go through and around it
+                let n = rng.below(16 * 1024 + 1);
+                let (mut e, mut a) = (Vec::new(), Vec::new());
+                let re = outcome((&mut expected).take(n).read_to_end(&mut e)).map(|n| n as u64);
+                let ra = outcome((&mut actual).take(n).read_to_end(&mut a)).map(|n| n as u64);
+                assert_eq!(e, a, "step {}", step);
+                (re, ra)
+            }
+            5 => {
+                let (mut e, mut a) = (Vec::new(), Vec::new());
+                let re = outcome(expected.read_until(b'\n', &mut e));
+                let ra = outcome(actual.read_until(b'\n', &mut a));
+                assert_eq!(e, a, "step {}", step);
+                (re.map(|n| n as u64), ra.map(|n| n as u64))
+            }
+            _ => {
+                let n = rng.below(64) as usize;
+                let e = expected.fill_buf().unwrap();
+                let a = actual.fill_buf().unwrap();
+                // Buffers differ in size, but both hold what comes next
+                let common = e.len().min(a.len());
+                assert_eq!(e[..common], a[..common], "step {}", step);
+                assert_eq!(e.is_empty(), a.is_empty(), "step {}", step);
+                let n = n.min(common);
+                expected.consume(n);
+                actual.consume(n);
+                (Ok(n as u64), Ok(n as u64))
+            }
+        };
+        assert_eq!(e, a, "step {}", step);
+        assert_eq!(
+            expected.stream_position().unwrap(),
+            actual.stream_position().unwrap(),
+            "step {}",
+            step
+        );
+    }
+}
+
+fn pack(dir: &Path) -> read::Archive {
+    write::Archive::from_path(dir.join("root"))
+        .unwrap()
+        .write_to_path(dir.join("out.klu"))
+        .unwrap();
+    read::Archive::from_path(dir.join("out.klu")).unwrap()
+}
+
+fn check(dir: &Path, archive: &read::Archive, name: &str) {
+    let path = dir.join("root").join(name);
+    let len = std::fs::metadata(&path).unwrap().len();
+    let file = archive.get_virtual(Path::new("root").join(name)).unwrap();
+    assert_eq!(file.len(), len);
+    conform(
+        BufReader::new(std::fs::File::open(&path).unwrap()),
+        file,
+        len,
+    );
+}
+
+#[test]
+fn regular_files() {
+    let dir = scratch("regular_files");
+    for (name, len) in &[("empty", 0), ("small", 100), ("large", 50_000)] {
+        std::fs::write(dir.join("root").join(name), content(*len)).unwrap();
+    }
+    let archive = pack(&dir);
+    for name in &["empty", "small", "large"] {
+        check(&dir, &archive, name);
+    }
+    // The same through an archive in memory
+    let bytes = std::fs::read(dir.join("out.klu")).unwrap();
+    let archive = read::Archive::from_bytes(bytes).unwrap();
+    check(&dir, &archive, "large");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sparse_file() {
+    use std::io::Write;
+    let dir = scratch("sparse_file");
+    let mut file = std::fs::File::create(dir.join("root/sparse")).unwrap();
+    file.set_len(1 << 20).unwrap();
+    for offset in &[0, 300_000, 900_000] {
+        file.seek(SeekFrom::Start(*offset)).unwrap();
+        file.write_all(&content(20_000)).unwrap();
+    }
+    drop(file);
+    let archive = pack(&dir);
+    check(&dir, &archive, "sparse");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn independent_positions() {
+    let dir = scratch("independent_positions");
+    std::fs::write(dir.join("root/file"), content(20_000)).unwrap();
+    let archive = pack(&dir);
+    let mut first = archive.get_virtual("root/file").unwrap();
+    first.seek(SeekFrom::Start(10_000)).unwrap();
+    let mut second = first.clone();
+    let mut a = [0; 100];
+    let mut b = [0; 100];
+    first.read_exact(&mut a).unwrap();
+    second.read_exact(&mut b).unwrap();
+    assert_eq!(a, b);
+    assert_eq!(&a[..], &content(20_000)[10_000..10_100]);
+    first.seek(SeekFrom::Start(0)).unwrap();
+    assert_eq!(second.stream_position().unwrap(), 10_100);
+    std::fs::remove_dir_all(dir).unwrap();
+}